@@ -0,0 +1,31 @@
+// Every allocator module which does not depend on the kernel. The kernel's allocator.rs declares
+// the same modules, so `super::` and `crate::allocator::` paths resolve the same way in both.
+
+#[path = "../../src/lib/allocator/arena.rs"]
+pub mod arena;
+#[path = "../../src/lib/allocator/bump.rs"]
+pub mod bump;
+#[path = "../../src/lib/allocator/debug.rs"]
+pub mod debug;
+#[path = "../../src/lib/allocator/external_linked_list.rs"]
+pub mod external_linked_list;
+#[path = "../../src/lib/allocator/fixed_size_block.rs"]
+pub mod fixed_size_block;
+#[path = "../../src/lib/allocator/linked_list.rs"]
+pub mod linked_list;
+#[path = "../../src/lib/allocator/stats.rs"]
+pub mod stats;
+#[path = "../../src/lib/allocator/tlsf.rs"]
+pub mod tlsf;
+#[path = "../../src/lib/allocator/util.rs"]
+mod util;
+#[path = "../../src/lib/allocator/validate.rs"]
+pub mod validate;
+
+pub use util::Locked;
+use util::align_up;
+
+/// There are no page tables to map more heap with, so heaps on the host never grow.
+fn grow_heap(_heap_end: usize, _size: usize) -> bool {
+    false
+}
@@ -0,0 +1,39 @@
+// Runs the tests of the heap allocators on the host, so that they do not need QEMU:
+//
+//     cd host-tests && cargo test
+//     cd host-tests && cargo miri test
+//
+// The allocator modules are compiled straight from the kernel sources (see allocator.rs), and
+// their #[test_case]s are run by test_runner below instead of the kernel's.
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![feature(allocator_api)]
+
+extern crate alloc;
+
+pub mod allocator;
+
+#[cfg(test)]
+trait Testable {
+    fn run(&self);
+}
+
+#[cfg(test)]
+impl<T> Testable for T
+where
+    T: Fn(),
+{
+    fn run(&self) {
+        print!("{}...\t", core::any::type_name::<T>());
+        self();
+        println!("[ok]");
+    }
+}
+
+#[cfg(test)]
+fn test_runner(tests: &[&dyn Testable]) {
+    println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+}
@@ -1,29 +1,224 @@
-use fixed_size_block::FixedSizeBlockAllocator;
-use spin::MutexGuard;
+use crate::memory::{
+    self, KernelMemory,
+    demand_paging::{self, ReserveError},
+};
+use bootloader::bootinfo::MemoryMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use stats::{AllocatorStats, HeapStats, MemInfo};
 use x86_64::{
     VirtAddr,
     structures::paging::{
-        FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError,
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError,
     },
 };
 
+pub mod arena;
 pub mod bump;
+pub mod debug;
+pub mod external_linked_list;
 pub mod fixed_size_block;
 pub mod linked_list;
+pub mod oom;
+pub mod slab_cache;
+pub mod stats;
+pub mod tlsf;
+mod util;
+pub mod validate;
+
+pub use util::Locked;
+use util::align_up;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// The heap grows on demand, but never beyond this size, unless it was sized from the memory map
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+
+/// Size the heap may grow to, see heap_limit.
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+/// How much of the usable physical memory the heap may take up, for `init_heap_from_memory_map`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapSizing {
+    /// Share of the usable memory, in percent
+    pub percent: usize,
+    pub min_size: usize,
+    pub max_size: usize,
+}
 
+impl HeapSizing {
+    /// A quarter of the usable memory, between 1 MiB and 1 GiB. That is a little under 16 MiB with
+    /// `qemu -m 64M` and a little under 1 GiB with `-m 4G`, as the memory taken by the bootloader,
+    /// the kernel and the DMA zone is not usable.
+    pub const DEFAULT: HeapSizing = HeapSizing {
+        percent: 25,
+        min_size: 1024 * 1024,
+        max_size: 1024 * 1024 * 1024,
+    };
+
+    /// Returns the heap size for the given amount of usable memory, rounded down to whole pages but
+    /// not below `min_size` rounded up to whole pages.
+    ///
+    /// Panics if `percent` is over 100 or `min_size` is larger than `max_size`.
+    pub fn heap_size(&self, usable_memory: u64) -> usize {
+        assert!(self.percent <= 100, "heap share over 100 percent");
+        assert!(
+            self.min_size <= self.max_size,
+            "min_size larger than max_size"
+        );
+
+        let share = usable_memory / 100 * self.percent as u64;
+        let share = usize::try_from(share).unwrap_or(usize::MAX);
+        let min_size = align_up(self.min_size, 4096);
+        share.clamp(min_size, self.max_size.max(min_size)) & !(4096 - 1)
+    }
+}
+
+impl Default for HeapSizing {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+// The global allocator is chosen with exactly one of the `alloc-*` cargo features
+const _: () = assert!(
+    cfg!(feature = "alloc-bump") as usize
+        + cfg!(feature = "alloc-linked-list") as usize
+        + cfg!(feature = "alloc-fixed-block") as usize
+        + cfg!(feature = "alloc-external-ll") as usize
+        + cfg!(feature = "alloc-tlsf") as usize
+        == 1,
+    "exactly one of the alloc-* features must be enabled (try --no-default-features)"
+);
+
+#[cfg(feature = "alloc-bump")]
+type HeapAllocator = bump::BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
+type HeapAllocator = linked_list::LinkedListAllocator;
+#[cfg(feature = "alloc-fixed-block")]
+type HeapAllocator = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "alloc-external-ll")]
+type HeapAllocator = external_linked_list::ExternalLinkedListAllocator;
+#[cfg(feature = "alloc-tlsf")]
+type HeapAllocator = tlsf::TlsfAllocator;
+
+// OomHandler runs the memory pressure callbacks before an allocation fails, it derefs to the
+// allocator it wraps
+#[cfg(not(feature = "alloc-debug"))]
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static ALLOCATOR: oom::OomHandler<Locked<HeapAllocator>> =
+    oom::OomHandler::new(Locked::new(HeapAllocator::new()));
+
+// `alloc-debug` wraps whichever allocator was chosen, DebugAllocator derefs to it
+#[cfg(feature = "alloc-debug")]
+#[global_allocator]
+static ALLOCATOR: oom::OomHandler<debug::DebugAllocator<Locked<HeapAllocator>>> =
+    oom::OomHandler::new(debug::DebugAllocator::new(
+        Locked::new(HeapAllocator::new()),
+    ));
+
+/// Returns the current usage of the global allocator.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
+/// Returns a snapshot of the global allocator's usage, e.g. for `println!("{}", meminfo())`.
+pub fn meminfo() -> MemInfo {
+    let allocator = ALLOCATOR.lock();
+    MemInfo {
+        heap: allocator.stats(),
+        size_classes: allocator.size_classes(),
+    }
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
 ) -> Result<(), MapToError<Size4KiB>> {
+    map_heap_pages(HEAP_START, HEAP_SIZE, mapper, frame_allocator)?;
+
+    let mut allocator = ALLOCATOR.lock();
+    unsafe { allocator.init(HEAP_START, HEAP_SIZE) };
+    allocator.enable_growth();
+
+    Ok(())
+}
+
+/// Initialises the heap with its maximum size, without mapping any of it.
+///
+/// The whole heap is reserved for demand paging, so pages only get backed by frames once they are
+/// touched. Requires the kernel memory to be initialised and the page fault handler to be loaded.
+pub fn init_demand_paged_heap() -> Result<(), ReserveError> {
+    init_demand_paged_heap_with_size(HEAP_MAX_SIZE)
+}
+
+/// Like `init_demand_paged_heap`, but sizes the heap from the usable memory in the memory map, so
+/// that the same kernel makes good use of both small and large machines.
+///
+/// Returns the chosen heap size, which also becomes the heap_limit.
+pub fn init_heap_from_memory_map(
+    memory_map: &MemoryMap,
+    sizing: HeapSizing,
+) -> Result<usize, ReserveError> {
+    let size = sizing.heap_size(memory::usable_memory(memory_map));
+    init_demand_paged_heap_with_size(size)?;
+    Ok(size)
+}
+
+fn init_demand_paged_heap_with_size(size: usize) -> Result<(), ReserveError> {
+    demand_paging::reserve(VirtAddr::new(HEAP_START as u64), size as u64)?;
+    HEAP_LIMIT.store(size, Ordering::Relaxed);
+
+    let mut allocator = ALLOCATOR.lock();
+    unsafe { allocator.init(HEAP_START, size) };
+    allocator.enable_growth();
+
+    Ok(())
+}
+
+/// Returns the size the heap may grow to: HEAP_MAX_SIZE, unless it was sized from the memory map.
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Maps `size` bytes of the heap right after `heap_end`, so that the heap can be extended.
+///
+/// Only the global allocator calls this, see `enable_growth` of the allocators.
+///
+/// Returns false if the heap would grow past its limit, if the heap or the kernel memory have not
+/// been initialised yet, or if we run out of frames.
+fn grow_heap(heap_end: usize, size: usize) -> bool {
+    if heap_end < HEAP_START || heap_end + size > HEAP_START + heap_limit() {
+        return false;
+    }
+
+    let mut kernel_memory = memory::KERNEL_MEMORY.lock();
+    let Some(KernelMemory {
+        mapper,
+        frame_allocator,
+    }) = kernel_memory.as_mut()
+    else {
+        return false;
+    };
+    map_heap_pages(heap_end, size, mapper, frame_allocator).is_ok()
+}
+
+/// Maps every page in `start..start + size` to a newly allocated frame.
+///
+/// If a page cannot be mapped, the pages mapped so far are unmapped and their frames freed again,
+/// so that a later attempt can map the same range.
+fn map_heap_pages<F>(
+    start: usize,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut F,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    F: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
 
         // Create start end end pages from addresses
         let heap_start_page = Page::containing_address(heap_start);
@@ -34,52 +229,87 @@ pub fn init_heap(
     };
 
     for page in page_range {
-        // Allocate a frame to map to
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-
-        // Map the every page to the new frame
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
-
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        if let Err(err) = map_heap_page(page, mapper, frame_allocator) {
+            // Roll back, so that the pages are not left mapped without belonging to the heap
+            for mapped in Page::range(page_range.start, page) {
+                let (frame, flush) = mapper
+                    .unmap(mapped)
+                    .expect("heap page mapped above is gone");
+                flush.flush();
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+            return Err(err);
+        }
     }
 
     Ok(())
 }
 
-/// A generic wrapper around spin::Mutex to get around the orphan rules
-pub struct Locked<A> {
-    inner: spin::Mutex<A>,
-}
+/// Maps a single heap page to a newly allocated frame.
+fn map_heap_page<F>(
+    page: Page<Size4KiB>,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut F,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    F: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    // Allocate a frame to map to
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
 
-impl<A> Locked<A> {
-    pub const fn new(inner: A) -> Self {
-        Locked {
-            inner: spin::Mutex::new(inner),
+    // Map the page to the new frame, giving the frame back if that fails
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(err)
         }
     }
+}
+
+#[cfg(test)]
+mod heap_sizing {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test_case]
+    fn takes_share_of_usable_memory() {
+        assert_eq!(HeapSizing::DEFAULT.heap_size(64 * MIB), 16 * 1024 * 1024);
+    }
 
-    pub fn lock(&self) -> MutexGuard<A> {
-        self.inner.lock()
+    #[test_case]
+    fn clamped_to_min_and_max() {
+        let sizing = HeapSizing::DEFAULT;
+        assert_eq!(sizing.heap_size(2 * MIB), sizing.min_size);
+        assert_eq!(sizing.heap_size(4096 * MIB), sizing.max_size);
+        assert_eq!(sizing.heap_size(64 * 1024 * MIB), sizing.max_size);
     }
-}
 
-/// Align the given address upwards to alignment
-///
-/// Requires that `alignment` is a power of two, which is guaranteed by
-/// core::alloc::Layout::align()
-fn align_up(addr: usize, alignment: usize) -> usize {
-    // e.g.
-    // alignment = 8    = 0...01000
-    // alignment - 1    = 0...00111
-    // !(alignment - 1) = 1...11000
-    // addr & !(alignment - 1) will remove the last 3 bits, effectively aligning downwards
-    // since we want to align upwards, we add (alignment - 1) first
-    let ones_below = alignment - 1;
-    let align_down_mask = !(ones_below);
-    (addr + ones_below) & align_down_mask
+    #[test_case]
+    fn rounded_down_to_pages() {
+        let sizing = HeapSizing {
+            percent: 50,
+            min_size: 0,
+            max_size: usize::MAX,
+        };
+        assert_eq!(sizing.heap_size(3 * 4096 + 100), 4096);
+    }
+
+    #[test_case]
+    fn rounding_keeps_min_size() {
+        let sizing = HeapSizing {
+            percent: 25,
+            min_size: 4096 + 1,
+            max_size: 2 * 4096 + 1,
+        };
+        assert_eq!(sizing.heap_size(0), 2 * 4096);
+        assert_eq!(sizing.heap_size(64 * MIB), 2 * 4096);
+    }
 }
@@ -0,0 +1,232 @@
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    cell::Cell,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ptr::{self, NonNull},
+};
+
+/// A bump allocator over a memory region supplied by the caller, for many short-lived allocations
+/// which are all freed at once.
+///
+/// Implements `Allocator`, so collections can be put into the arena with e.g.
+/// `Vec::new_in(&arena)`. Freeing only gives memory back if it is the most recent allocation, all
+/// other memory is only reused after `reset`, or when a `scope` ends.
+pub struct BumpArena<'a> {
+    start: usize,
+    end: usize,
+    next: Cell<usize>,
+    _region: PhantomData<&'a mut [MaybeUninit<u8>]>,
+}
+
+impl<'a> BumpArena<'a> {
+    pub fn new(region: &'a mut [MaybeUninit<u8>]) -> Self {
+        let start = region.as_mut_ptr() as usize;
+        BumpArena {
+            start,
+            end: start + region.len(),
+            next: Cell::new(start),
+            _region: PhantomData,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Bytes handed out since the arena was created or last reset, including alignment padding.
+    pub fn used(&self) -> usize {
+        self.next.get() - self.start
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.end - self.next.get()
+    }
+
+    /// Frees every allocation at once.
+    ///
+    /// Takes `&mut self`, so nothing allocated in the arena can still be alive.
+    pub fn reset(&mut self) {
+        self.next.set(self.start);
+    }
+
+    /// Runs `f`, and frees everything it allocated in the arena once it returns.
+    ///
+    /// Allocations made before the scope are kept. Nothing allocated inside the scope can be
+    /// returned from it, as it borrows the arena only for the duration of `f`.
+    pub fn scope<R>(&mut self, f: impl FnOnce(&Self) -> R) -> R {
+        let mark = self.next.get();
+        let result = f(self);
+        self.next.set(mark);
+        result
+    }
+
+    /// Whether the block at ptr with the given size is the most recent allocation.
+    fn is_last(&self, ptr: NonNull<u8>, size: usize) -> bool {
+        ptr.as_ptr() as usize + size == self.next.get()
+    }
+}
+
+unsafe impl Allocator for BumpArena<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let alloc_start = super::align_up(self.next.get(), layout.align());
+        let alloc_end = alloc_start.checked_add(layout.size()).ok_or(AllocError)?;
+        if alloc_end > self.end {
+            return Err(AllocError);
+        }
+
+        self.next.set(alloc_end);
+        let ptr = NonNull::new(alloc_start as *mut u8).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if self.is_last(ptr, layout.size()) {
+            self.next.set(ptr.as_ptr() as usize);
+        }
+    }
+
+    /// Grows the most recent allocation in place, e.g. when pushing onto the last Vec created in
+    /// the arena.
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let aligned = ptr.as_ptr() as usize % new_layout.align() == 0;
+        if aligned && self.is_last(ptr, old_layout.size()) {
+            let new_end = (ptr.as_ptr() as usize)
+                .checked_add(new_layout.size())
+                .ok_or(AllocError)?;
+            if new_end > self.end {
+                return Err(AllocError);
+            }
+            self.next.set(new_end);
+            return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+        }
+
+        let new_ptr = self.allocate(new_layout)?;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                old_layout.size(),
+            );
+        }
+        Ok(new_ptr)
+    }
+}
+
+#[cfg(test)]
+mod bump_arena {
+    use super::*;
+    use alloc::{boxed::Box, vec::Vec};
+
+    const REGION_SIZE: usize = 1024;
+
+    /// The tests count padding bytes, so the region must start aligned for every layout they use.
+    #[repr(align(16))]
+    struct Region([MaybeUninit<u8>; REGION_SIZE]);
+
+    fn new_region() -> Region {
+        Region([MaybeUninit::uninit(); REGION_SIZE])
+    }
+
+    #[test_case]
+    fn collections_can_live_in_the_arena() {
+        let mut region = new_region();
+        let arena = BumpArena::new(&mut region.0);
+
+        let mut vec = Vec::new_in(&arena);
+        vec.extend(0..100u32);
+        let boxed = Box::new_in(41u64, &arena);
+
+        assert_eq!(vec.iter().sum::<u32>(), 4950);
+        assert_eq!(*boxed, 41);
+        assert!(arena.used() >= 100 * 4 + 8);
+    }
+
+    #[test_case]
+    fn allocations_are_aligned() {
+        let mut region = new_region();
+        let arena = BumpArena::new(&mut region.0);
+
+        arena.allocate(Layout::new::<u8>()).unwrap();
+        for align in [2, 8, 64, 256] {
+            let layout = Layout::from_size_align(1, align).unwrap();
+            let ptr = arena.allocate(layout).unwrap();
+            assert_eq!(ptr.cast::<u8>().as_ptr() as usize % align, 0);
+        }
+    }
+
+    #[test_case]
+    fn exhausted_arena_fails() {
+        let mut region = new_region();
+        let arena = BumpArena::new(&mut region.0);
+
+        let layout = Layout::from_size_align(REGION_SIZE / 2 + 1, 1).unwrap();
+        assert!(arena.allocate(layout).is_ok());
+        assert!(arena.allocate(layout).is_err());
+        assert_eq!(arena.remaining(), REGION_SIZE / 2 - 1);
+    }
+
+    #[test_case]
+    fn last_allocation_is_freed() {
+        let mut region = new_region();
+        let arena = BumpArena::new(&mut region.0);
+
+        let layout = Layout::new::<u64>();
+        let first = arena.allocate(layout).unwrap();
+        let second = arena.allocate(layout).unwrap();
+
+        // only the most recent allocation can be given back
+        unsafe { arena.deallocate(first.cast(), layout) };
+        assert_eq!(arena.used(), 16);
+        unsafe { arena.deallocate(second.cast(), layout) };
+        assert_eq!(arena.used(), 8);
+    }
+
+    #[test_case]
+    fn last_vec_grows_in_place() {
+        let mut region = new_region();
+        let arena = BumpArena::new(&mut region.0);
+
+        let mut vec: Vec<u8, _> = Vec::with_capacity_in(8, &arena);
+        let ptr = vec.as_ptr();
+        vec.extend(0..200);
+        assert_eq!(vec.as_ptr(), ptr);
+        assert_eq!(arena.used(), vec.capacity());
+    }
+
+    #[test_case]
+    fn reset_frees_everything() {
+        let mut region = new_region();
+        let mut arena = BumpArena::new(&mut region.0);
+
+        let vec: Vec<u64, _> = Vec::with_capacity_in(64, &arena);
+        drop(vec);
+        arena.allocate(Layout::new::<u64>()).unwrap();
+        assert!(arena.used() > 0);
+
+        arena.reset();
+        assert_eq!(arena.used(), 0);
+        assert_eq!(arena.remaining(), REGION_SIZE);
+    }
+
+    #[test_case]
+    fn scope_frees_what_it_allocated() {
+        let mut region = new_region();
+        let mut arena = BumpArena::new(&mut region.0);
+        arena.allocate(Layout::new::<u64>()).unwrap();
+
+        let sum = arena.scope(|arena| {
+            let mut vec = Vec::new_in(arena);
+            vec.extend(1..=10u64);
+            vec.iter().sum::<u64>()
+        });
+
+        assert_eq!(sum, 55);
+        assert_eq!(arena.used(), 8);
+    }
+}
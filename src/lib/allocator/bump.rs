@@ -1,6 +1,10 @@
+use super::{
+    stats::{AllocatorStats, HeapStats, Usage},
+    validate::{Corruption, Validate, ensure},
+};
 use core::{
     alloc::{GlobalAlloc, Layout},
-    cmp, ptr,
+    ptr,
 };
 
 pub struct BumpAllocator {
@@ -8,6 +12,9 @@ pub struct BumpAllocator {
     heap_end: usize,
     next: usize,
     allocations: usize,
+    /// Set by enable_growth
+    growable: bool,
+    usage: Usage,
 }
 
 impl BumpAllocator {
@@ -17,6 +24,8 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            growable: false,
+            usage: Usage::new(),
         }
     }
 
@@ -25,6 +34,40 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
+
+    /// Lets the allocator map more pages after the end of its heap when it runs out of memory.
+    ///
+    /// Only the global allocator may do this, as the pages after the heap are reserved for it. Any
+    /// other instance would hand out memory which the global allocator considers free.
+    pub fn enable_growth(&mut self) {
+        self.growable = true;
+    }
+}
+
+impl AllocatorStats for BumpAllocator {
+    /// Freed memory is only reused once every allocation is freed, so only the space after `next`
+    /// counts as free.
+    fn stats(&self) -> HeapStats {
+        let free = self.heap_end - self.next;
+        self.usage.stats(free, Some(free))
+    }
+}
+
+impl Validate for BumpAllocator {
+    /// There is no free list, only `next` which must stay inside the heap and go back to the start
+    /// once every allocation is freed.
+    fn validate(&self) -> Result<(), Corruption> {
+        ensure(
+            (self.heap_start..=self.heap_end).contains(&self.next),
+            self.next,
+            "next is outside of the heap",
+        )?;
+        ensure(
+            self.allocations > 0 || self.next == self.heap_start,
+            self.next,
+            "next not reset after every allocation was freed",
+        )
+    }
 }
 
 unsafe impl GlobalAlloc for super::Locked<BumpAllocator> {
@@ -42,18 +85,25 @@ unsafe impl GlobalAlloc for super::Locked<BumpAllocator> {
         };
 
         if alloc_end > locked_self.heap_end {
-            ptr::null_mut()
-        } else {
-            locked_self.allocations += 1;
-            locked_self.next = alloc_end;
-            alloc_start as *mut u8
+            // Out of memory, try to map enough pages after the end of the heap
+            let growth = super::align_up(alloc_end - locked_self.heap_end, 4096);
+            if !locked_self.growable || !super::grow_heap(locked_self.heap_end, growth) {
+                return ptr::null_mut();
+            }
+            locked_self.heap_end += growth;
         }
+
+        locked_self.allocations += 1;
+        locked_self.usage.record_alloc(layout.size());
+        locked_self.next = alloc_end;
+        alloc_start as *mut u8
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
         let mut locked_self = self.lock();
 
-        locked_self.allocations = cmp::max(0, locked_self.allocations - 1);
+        locked_self.usage.record_dealloc(layout.size());
+        locked_self.allocations = locked_self.allocations.saturating_sub(1);
         if locked_self.allocations == 0 {
             locked_self.next = locked_self.heap_start;
         }
@@ -0,0 +1,433 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    ops::Deref,
+    ptr,
+};
+use spin::Mutex;
+
+/// Number of guard bytes before and after every allocation.
+const GUARD_SIZE: usize = 16;
+/// Written into the guard bytes, any other value means something wrote out of bounds.
+const GUARD_BYTE: u8 = 0xfd;
+/// Written into freshly allocated memory, to expose reads of uninitialised memory.
+const ALLOC_BYTE: u8 = 0xcd;
+/// Written into freed memory, to expose use after free.
+const FREED_BYTE: u8 = 0xdd;
+
+/// Number of live allocations that can be tracked at the same time.
+const MAX_TRACKED: usize = 512;
+/// Number of freed pointers remembered to tell a double free from a free of a bogus pointer.
+const MAX_RECENTLY_FREED: usize = 64;
+
+/// A heap corruption detected by DebugAllocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    /// The pointer was freed before, and not allocated again since
+    DoubleFree { ptr: usize },
+    /// The pointer was never returned by the allocator
+    UnknownPointer { ptr: usize },
+    /// The pointer is freed with a different layout than it was allocated with
+    LayoutMismatch {
+        ptr: usize,
+        allocated: Layout,
+        freed: Layout,
+    },
+    /// Something wrote into the guard bytes before the allocation
+    Underrun {
+        ptr: usize,
+        layout: Layout,
+        offset: usize,
+        found: u8,
+    },
+    /// Something wrote into the guard bytes after the allocation
+    Overrun {
+        ptr: usize,
+        layout: Layout,
+        offset: usize,
+        found: u8,
+    },
+}
+
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeapError::DoubleFree { ptr } => write!(f, "double free of {ptr:#x}"),
+            HeapError::UnknownPointer { ptr } => {
+                write!(f, "free of {ptr:#x}, which was never allocated")
+            }
+            HeapError::LayoutMismatch {
+                ptr,
+                allocated,
+                freed,
+            } => write!(
+                f,
+                "{ptr:#x} allocated with size {} align {}, but freed with size {} align {}",
+                allocated.size(),
+                allocated.align(),
+                freed.size(),
+                freed.align()
+            ),
+            HeapError::Underrun {
+                ptr,
+                layout,
+                offset,
+                found,
+            } => write!(
+                f,
+                "buffer underrun before {ptr:#x} (size {}): guard byte at -{offset} is {found:#x}",
+                layout.size()
+            ),
+            HeapError::Overrun {
+                ptr,
+                layout,
+                offset,
+                found,
+            } => write!(
+                f,
+                "buffer overrun after {ptr:#x} (size {}): guard byte at +{offset} is {found:#x}",
+                layout.size()
+            ),
+        }
+    }
+}
+
+/// Bookkeeping of DebugAllocator, kept outside of the heap it is checking.
+struct Tracker {
+    live: [Option<(usize, Layout)>; MAX_TRACKED],
+    /// Live allocations which did not fit into `live`
+    untracked: usize,
+    /// Ring buffer of recently freed pointers
+    recently_freed: [usize; MAX_RECENTLY_FREED],
+    next_freed: usize,
+}
+
+/// A GlobalAlloc wrapper which catches heap corruption, at the cost of speed and memory.
+///
+/// Every allocation is surrounded by guard bytes, which are checked when it is freed. Fresh memory
+/// is filled with `ALLOC_BYTE` and freed memory with `FREED_BYTE`, so that uses of uninitialised
+/// or freed memory stand out. Live allocations are kept in a fixed size table (the heap cannot be
+/// used for this), so that double frees and frees with the wrong layout are caught too.
+///
+/// Any corruption panics with a description of what went wrong.
+pub struct DebugAllocator<A> {
+    inner: A,
+    tracker: Mutex<Tracker>,
+}
+
+impl<A> DebugAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        DebugAllocator {
+            inner,
+            tracker: Mutex::new(Tracker {
+                live: [None; MAX_TRACKED],
+                untracked: 0,
+                recently_freed: [0; MAX_RECENTLY_FREED],
+                next_freed: 0,
+            }),
+        }
+    }
+
+    /// Checks the guard bytes of every tracked live allocation.
+    pub fn check_live(&self) -> Result<(), HeapError> {
+        let tracker = self.tracker.lock();
+        for &(ptr, layout) in tracker.live.iter().flatten() {
+            unsafe { check_guards(ptr, layout)? };
+        }
+        Ok(())
+    }
+
+    /// Checks that `ptr` can be freed with `layout`.
+    ///
+    /// On success, the allocation is no longer tracked.
+    fn check_dealloc(&self, ptr: usize, layout: Layout) -> Result<(), HeapError> {
+        let mut tracker = self.tracker.lock();
+        match tracker
+            .live
+            .iter()
+            .position(|e| e.is_some_and(|(p, _)| p == ptr))
+        {
+            Some(idx) => {
+                let (_, allocated) = tracker.live[idx].unwrap();
+                if allocated != layout {
+                    return Err(HeapError::LayoutMismatch {
+                        ptr,
+                        allocated,
+                        freed: layout,
+                    });
+                }
+                unsafe { check_guards(ptr, layout)? };
+                tracker.live[idx] = None;
+            }
+            None if tracker.recently_freed.contains(&ptr) => {
+                return Err(HeapError::DoubleFree { ptr });
+            }
+            // The allocation may simply not have fit into the table
+            None if tracker.untracked > 0 => {
+                unsafe { check_guards(ptr, layout)? };
+                tracker.untracked -= 1;
+            }
+            None => return Err(HeapError::UnknownPointer { ptr }),
+        }
+
+        let next = tracker.next_freed;
+        tracker.recently_freed[next] = ptr;
+        tracker.next_freed = (next + 1) % MAX_RECENTLY_FREED;
+        Ok(())
+    }
+}
+
+impl<A> Deref for DebugAllocator<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+/// Bytes in front of the allocation, which must keep the allocation aligned.
+fn front_size(layout: Layout) -> usize {
+    GUARD_SIZE.max(layout.align())
+}
+
+/// Layout of the block requested from the wrapped allocator, including guard bytes.
+fn guarded_layout(layout: Layout) -> Option<Layout> {
+    let size = front_size(layout)
+        .checked_add(layout.size())?
+        .checked_add(GUARD_SIZE)?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+/// Checks the guard bytes on both sides of the allocation at `ptr`.
+///
+/// Unsafe because `ptr` must be an allocation made by DebugAllocator with `layout`.
+unsafe fn check_guards(ptr: usize, layout: Layout) -> Result<(), HeapError> {
+    for offset in 1..=GUARD_SIZE {
+        let found = unsafe { ((ptr - offset) as *const u8).read_volatile() };
+        if found != GUARD_BYTE {
+            return Err(HeapError::Underrun {
+                ptr,
+                layout,
+                offset,
+                found,
+            });
+        }
+    }
+    for offset in 0..GUARD_SIZE {
+        let found = unsafe { ((ptr + layout.size() + offset) as *const u8).read_volatile() };
+        if found != GUARD_BYTE {
+            return Err(HeapError::Overrun {
+                ptr,
+                layout,
+                offset,
+                found,
+            });
+        }
+    }
+    Ok(())
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(guarded) = guarded_layout(layout) else {
+            return ptr::null_mut();
+        };
+        let block = unsafe { self.inner.alloc(guarded) };
+        if block.is_null() {
+            return block;
+        }
+
+        let front = front_size(layout);
+        unsafe {
+            block.write_bytes(GUARD_BYTE, front);
+            block.add(front).write_bytes(ALLOC_BYTE, layout.size());
+            block
+                .add(front + layout.size())
+                .write_bytes(GUARD_BYTE, GUARD_SIZE);
+        }
+
+        let ptr = unsafe { block.add(front) };
+        let mut tracker = self.tracker.lock();
+        // The address is live again, freeing it is no longer a double free
+        for freed in tracker.recently_freed.iter_mut() {
+            if *freed == ptr as usize {
+                *freed = 0;
+            }
+        }
+        match tracker.live.iter_mut().find(|e| e.is_none()) {
+            Some(entry) => *entry = Some((ptr as usize, layout)),
+            None => tracker.untracked += 1,
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Err(error) = self.check_dealloc(ptr as usize, layout) {
+            panic!("heap corruption detected: {error}");
+        }
+
+        let front = front_size(layout);
+        let guarded = guarded_layout(layout).unwrap();
+        unsafe {
+            let block = ptr.sub(front);
+            block.write_bytes(FREED_BYTE, guarded.size());
+            self.inner.dealloc(block, guarded);
+        }
+    }
+}
+
+#[cfg(test)]
+mod debug_allocator {
+    use super::*;
+    use crate::allocator::{Locked, linked_list::LinkedListAllocator};
+
+    #[repr(align(16))]
+    struct TestHeap([u8; 4096]);
+
+    /// Creates a DebugAllocator wrapping a LinkedListAllocator on `test_heap`.
+    fn new_allocator(test_heap: &mut TestHeap) -> DebugAllocator<Locked<LinkedListAllocator>> {
+        let allocator = DebugAllocator::new(Locked::new(LinkedListAllocator::new()));
+        unsafe {
+            allocator
+                .lock()
+                .init(test_heap.0.as_mut_ptr() as usize, test_heap.0.len())
+        };
+        allocator
+    }
+
+    #[test_case]
+    fn fresh_memory_is_filled_and_guarded() {
+        let mut test_heap = TestHeap([0; 4096]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+        for offset in 0..layout.size() {
+            assert_eq!(unsafe { ptr.add(offset).read() }, ALLOC_BYTE);
+        }
+        assert_eq!(unsafe { check_guards(ptr as usize, layout) }, Ok(()));
+        assert_eq!(allocator.check_live(), Ok(()));
+
+        unsafe { allocator.dealloc(ptr, layout) };
+        assert_eq!(unsafe { ptr.read() }, FREED_BYTE);
+    }
+
+    #[test_case]
+    fn alignment_is_kept() {
+        let mut test_heap = TestHeap([0; 4096]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(8, 64).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert_eq!(ptr as usize % 64, 0);
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+
+    #[test_case]
+    fn detects_overrun() {
+        let mut test_heap = TestHeap([0; 4096]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        unsafe { ptr.add(layout.size() + 2).write(0) };
+
+        assert_eq!(
+            allocator.check_live(),
+            Err(HeapError::Overrun {
+                ptr: ptr as usize,
+                layout,
+                offset: 2,
+                found: 0
+            })
+        );
+    }
+
+    #[test_case]
+    fn detects_underrun() {
+        let mut test_heap = TestHeap([0; 4096]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        unsafe { ptr.sub(1).write(0) };
+
+        assert_eq!(
+            allocator.check_dealloc(ptr as usize, layout),
+            Err(HeapError::Underrun {
+                ptr: ptr as usize,
+                layout,
+                offset: 1,
+                found: 0
+            })
+        );
+    }
+
+    #[test_case]
+    fn detects_layout_mismatch() {
+        let mut test_heap = TestHeap([0; 4096]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let wrong_layout = Layout::from_size_align(32, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+
+        assert_eq!(
+            allocator.check_dealloc(ptr as usize, wrong_layout),
+            Err(HeapError::LayoutMismatch {
+                ptr: ptr as usize,
+                allocated: layout,
+                freed: wrong_layout
+            })
+        );
+    }
+
+    #[test_case]
+    fn detects_double_free() {
+        let mut test_heap = TestHeap([0; 4096]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        unsafe { allocator.dealloc(ptr, layout) };
+
+        assert_eq!(
+            allocator.check_dealloc(ptr as usize, layout),
+            Err(HeapError::DoubleFree { ptr: ptr as usize })
+        );
+    }
+
+    #[test_case]
+    fn untracked_allocation_at_freed_address() {
+        let mut test_heap = TestHeap([0; 4096]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        unsafe { allocator.dealloc(ptr, layout) };
+
+        // with the table full, the next allocation at the same address is not tracked
+        let filler = Some((usize::MAX, layout));
+        allocator.tracker.lock().live = [filler; MAX_TRACKED];
+        let reused = unsafe { allocator.alloc(layout) };
+        assert_eq!(reused, ptr);
+        assert_eq!(allocator.tracker.lock().untracked, 1);
+
+        assert_eq!(allocator.check_dealloc(reused as usize, layout), Ok(()));
+        assert_eq!(allocator.tracker.lock().untracked, 0);
+    }
+
+    #[test_case]
+    fn detects_unknown_pointer() {
+        let mut test_heap = TestHeap([0; 4096]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let bogus = test_heap.0.as_ptr() as usize + 8;
+        assert_eq!(
+            allocator.check_dealloc(bogus, layout),
+            Err(HeapError::UnknownPointer { ptr: bogus })
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use super::{
+    Locked,
+    stats::{AllocatorStats, HeapStats, Usage},
+    validate::{Corruption, Validate},
+};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+use linked_list_allocator::Heap;
+
+/// Allocates from `heap`, growing it if it is out of memory and `growable` is set.
+///
+/// If the heap is exhausted, it is grown by enough pages to fit the layout and the allocation is
+/// retried.
+pub(super) fn allocate_growing(heap: &mut Heap, growable: bool, layout: Layout) -> *mut u8 {
+    if let Ok(ptr) = heap.allocate_first_fit(layout) {
+        return ptr.as_ptr();
+    }
+    if !growable {
+        return ptr::null_mut();
+    }
+
+    // The free space at the end of the heap may not be usable because of alignment, so make sure
+    // the new pages alone can fit the layout
+    let growth = super::align_up(layout.size() + layout.align(), 4096);
+    if !super::grow_heap(heap.top(), growth) {
+        return ptr::null_mut();
+    }
+    unsafe { heap.extend(growth) };
+
+    match heap.allocate_first_fit(layout) {
+        Ok(ptr) => ptr.as_ptr(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Uses the `linked_list_allocator` crate directly, as a reference for our own allocators.
+pub struct ExternalLinkedListAllocator {
+    heap: Heap,
+    /// Set by enable_growth
+    growable: bool,
+    usage: Usage,
+}
+
+impl ExternalLinkedListAllocator {
+    pub const fn new() -> Self {
+        ExternalLinkedListAllocator {
+            heap: Heap::empty(),
+            growable: false,
+            usage: Usage::new(),
+        }
+    }
+
+    /// Initialise the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee the given heap bounds are valid and that the heap is unused. This
+    /// method can only be called once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { self.heap.init(heap_start, heap_size) };
+    }
+
+    /// Lets the allocator map more pages after the end of its heap when it runs out of memory.
+    ///
+    /// Only the global allocator may do this, as the pages after the heap are reserved for it. Any
+    /// other instance would hand out memory which the global allocator considers free.
+    pub fn enable_growth(&mut self) {
+        self.growable = true;
+    }
+}
+
+impl Default for ExternalLinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AllocatorStats for ExternalLinkedListAllocator {
+    /// The heap does not expose its free regions, so the largest free block is not known.
+    fn stats(&self) -> HeapStats {
+        self.usage.stats(self.heap.free(), None)
+    }
+}
+
+impl Validate for ExternalLinkedListAllocator {
+    /// The heap keeps its free list private, so there is nothing to check.
+    fn validate(&self) -> Result<(), Corruption> {
+        Ok(())
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<ExternalLinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocator = &mut *self.lock();
+        let ptr = allocate_growing(&mut allocator.heap, allocator.growable, layout);
+        if !ptr.is_null() {
+            allocator.usage.record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new(ptr).unwrap();
+        let mut allocator = self.lock();
+        unsafe { allocator.heap.deallocate(ptr, layout) };
+        allocator.usage.record_dealloc(layout.size());
+    }
+}
@@ -1,6 +1,11 @@
-use super::Locked;
+use super::{
+    Locked,
+    stats::{AllocatorStats, HeapStats, SizeClassStats, SizeClasses, Usage},
+    validate::{Corruption, Validate, ensure},
+};
 use core::{
     alloc::{GlobalAlloc, Layout},
+    mem,
     ptr::{self, NonNull},
 };
 
@@ -16,17 +21,57 @@ struct ListNode {
 /// For allocations more than 2048 bytes, we will use the fallback allocator.
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
+pub const NUM_SIZE_CLASSES: usize = BLOCK_SIZES.len();
+
+const _: () = assert!(NUM_SIZE_CLASSES <= SizeClasses::MAX);
+
+/// Size class lists are refilled by splitting a whole slab, taken from the fallback allocator.
+/// Slabs are aligned to their size, so the slab of a block is found by rounding down.
+///
+/// Slabs of the small size classes are this large, the larger ones hold at least
+/// MIN_BLOCKS_PER_SLAB blocks, see slab_size.
+const SLAB_SIZE: usize = 4096;
+const MIN_BLOCKS_PER_SLAB: usize = 8;
+
+/// Written into the first block(s) of every slab, so that no table of slabs is needed.
+struct SlabHeader {
+    /// Index into `BLOCK_SIZES`
+    class: usize,
+    /// Blocks of this slab which are currently allocated
+    live_blocks: usize,
+}
+
+/// A heap allocator with a free list per block size, falling back to a linked list allocator for
+/// large allocations.
+///
+/// Empty size classes are refilled with a whole slab at once. Once every block of a slab is free
+/// again, the slab is returned to the fallback allocator, unless it is the only free memory of its
+/// size class (so that allocating and freeing a single block does not take a slab each time).
 pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    list_heads: [Option<&'static mut ListNode>; NUM_SIZE_CLASSES],
     fallback_allocator: linked_list_allocator::Heap,
+    /// Set by enable_growth
+    growable: bool,
+    /// Slabs per size class
+    slabs: [usize; NUM_SIZE_CLASSES],
+    /// Allocated blocks per size class
+    live_blocks: [usize; NUM_SIZE_CLASSES],
+    /// Length of each list in `list_heads`
+    free_blocks: [usize; NUM_SIZE_CLASSES],
+    usage: Usage,
 }
 
 impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
         const EMPTY: Option<&'static mut ListNode> = None;
         FixedSizeBlockAllocator {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            list_heads: [EMPTY; NUM_SIZE_CLASSES],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            growable: false,
+            slabs: [0; NUM_SIZE_CLASSES],
+            live_blocks: [0; NUM_SIZE_CLASSES],
+            free_blocks: [0; NUM_SIZE_CLASSES],
+            usage: Usage::new(),
         }
     }
 
@@ -38,15 +83,152 @@ impl FixedSizeBlockAllocator {
         unsafe { self.fallback_allocator.init(heap_start, heap_size) };
     }
 
-    /// Allocates using the fallback allocator.
+    /// Lets the allocator map more pages after the end of its heap when it runs out of memory.
+    ///
+    /// Only the global allocator may do this, as the pages after the heap are reserved for it. Any
+    /// other instance would hand out memory which the global allocator considers free.
+    pub fn enable_growth(&mut self) {
+        self.growable = true;
+    }
+
+    /// Allocates using the fallback allocator, growing the heap if it is out of memory and growth
+    /// is enabled.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        super::external_linked_list::allocate_growing(
+            &mut self.fallback_allocator,
+            self.growable,
+            layout,
+        )
+    }
+
+    /// Takes a slab from the fallback allocator and puts all of its blocks on the free list of the
+    /// given size class.
+    ///
+    /// Returns false if the fallback allocator is out of memory.
+    fn refill(&mut self, class: usize) -> bool {
+        let start = self.fallback_alloc(slab_layout(class));
+        if start.is_null() {
+            return false;
+        }
+
+        let start = start as usize;
+        unsafe {
+            (start as *mut SlabHeader).write(SlabHeader {
+                class,
+                live_blocks: 0,
+            })
+        };
+        let first_block = start + header_blocks(class) * BLOCK_SIZES[class];
+        // pushed in reverse, so that blocks are handed out in address order
+        for block in (first_block..start + slab_size(class))
+            .step_by(BLOCK_SIZES[class])
+            .rev()
+        {
+            unsafe { self.push_block(class, block) };
+        }
+        self.slabs[class] += 1;
+        true
+    }
+
+    /// Returns the slab to the fallback allocator, after removing its blocks from the free list.
+    ///
+    /// Every block of the slab must be free.
+    fn release_slab(&mut self, class: usize, start: usize) {
+        let slab_range = start..start + slab_size(class);
+
+        let mut list = self.list_heads[class].take();
+        while let Some(node) = list {
+            list = node.next.take();
+            if !slab_range.contains(&(node as *mut ListNode as usize)) {
+                node.next = self.list_heads[class].take();
+                self.list_heads[class] = Some(node);
+            }
         }
+        self.free_blocks[class] -= blocks_per_slab(class);
+        self.slabs[class] -= 1;
+
+        let ptr = NonNull::new(start as *mut u8).unwrap();
+        unsafe { self.fallback_allocator.deallocate(ptr, slab_layout(class)) };
+    }
+
+    /// Shrinks the fallback block at addr from old_size to new_size bytes without moving it, by
+    /// freeing its tail.
+    ///
+    /// Returns false if the tail is too small to hold a hole of the fallback allocator, which
+    /// rounds every block up to a multiple of a usize and needs two of them for a hole.
+    ///
+    /// Unsafe because the block must be allocated from the fallback allocator with old_size.
+    unsafe fn shrink_fallback(&mut self, addr: usize, old_size: usize, new_size: usize) -> bool {
+        let granularity = mem::align_of::<usize>();
+        let tail_start = super::align_up(addr + new_size, granularity);
+        let old_end = super::align_up(addr + old_size, granularity);
+        let tail = old_end - tail_start;
+        if tail > 0 && tail < 2 * mem::size_of::<usize>() {
+            return false;
+        }
+        if tail > 0 {
+            let ptr = NonNull::new(tail_start as *mut u8).unwrap();
+            let tail_layout = Layout::from_size_align(tail, 1).unwrap();
+            unsafe { self.fallback_allocator.deallocate(ptr, tail_layout) };
+        }
+
+        self.usage.record_realloc(old_size, new_size);
+        true
+    }
+
+    /// Puts the block at addr on the free list of the given size class.
+    ///
+    /// Unsafe because the block must be unused and of the given size class.
+    unsafe fn push_block(&mut self, class: usize, addr: usize) {
+        let new_node = ListNode {
+            next: self.list_heads[class].take(),
+        };
+        let new_node_ptr = addr as *mut ListNode;
+        unsafe {
+            new_node_ptr.write(new_node);
+            self.list_heads[class] = Some(&mut *new_node_ptr)
+        };
+        self.free_blocks[class] += 1;
+    }
+
+    /// Takes a block from the free list of the given size class.
+    fn pop_block(&mut self, class: usize) -> Option<*mut u8> {
+        let node = self.list_heads[class].take()?;
+        self.list_heads[class] = node.next.take();
+        self.free_blocks[class] -= 1;
+        // go through the address, a pointer derived from `node` may only access the ListNode and
+        // not the rest of the block
+        let addr = node as *mut ListNode as usize;
+        Some(addr as *mut u8)
     }
 }
 
+/// Size of the slabs of a size class, a power of two.
+const fn slab_size(class: usize) -> usize {
+    let size = BLOCK_SIZES[class] * MIN_BLOCKS_PER_SLAB;
+    if size > SLAB_SIZE { size } else { SLAB_SIZE }
+}
+
+fn slab_layout(class: usize) -> Layout {
+    Layout::from_size_align(slab_size(class), slab_size(class)).unwrap()
+}
+
+/// Number of blocks at the start of a slab which hold its SlabHeader.
+fn header_blocks(class: usize) -> usize {
+    mem::size_of::<SlabHeader>().div_ceil(BLOCK_SIZES[class])
+}
+
+fn blocks_per_slab(class: usize) -> usize {
+    slab_size(class) / BLOCK_SIZES[class] - header_blocks(class)
+}
+
+/// Returns the header of the slab which contains the block at addr.
+///
+/// Unsafe because the block must lie in a slab of the given size class.
+unsafe fn slab_header(class: usize, addr: usize) -> *mut SlabHeader {
+    (addr & !(slab_size(class) - 1)) as *mut SlabHeader
+}
+
 /// Choose an appropriate block size for the given layout.
 ///
 /// Returns an index into the `BLOCK_SIZES` array.
@@ -55,45 +237,377 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+impl AllocatorStats for FixedSizeBlockAllocator {
+    /// The fallback allocator does not expose its free regions, so the largest free block is not
+    /// known.
+    fn stats(&self) -> HeapStats {
+        let free_in_blocks: usize = BLOCK_SIZES
+            .iter()
+            .zip(self.free_blocks)
+            .map(|(size, count)| size * count)
+            .sum();
+        self.usage
+            .stats(self.fallback_allocator.free() + free_in_blocks, None)
+    }
+
+    fn size_classes(&self) -> Option<SizeClasses> {
+        Some(SizeClasses::new((0..NUM_SIZE_CLASSES).map(|idx| {
+            SizeClassStats {
+                block_size: BLOCK_SIZES[idx],
+                live_blocks: self.live_blocks[idx],
+                free_blocks: self.free_blocks[idx],
+            }
+        })))
+    }
+}
+
+impl Validate for FixedSizeBlockAllocator {
+    /// Checks that every free block lies in a slab of its size class, and that the free lists agree
+    /// with `free_blocks` and with the live blocks of the slabs.
+    ///
+    /// The fallback allocator keeps its free list private, so it is not checked.
+    fn validate(&self) -> Result<(), Corruption> {
+        for (class, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            let head = self.list_heads[class].as_deref();
+            let mut length = 0;
+            for node in core::iter::successors(head, |node| node.next.as_deref()) {
+                let addr = node as *const ListNode as usize;
+                ensure(
+                    addr % block_size == 0,
+                    addr,
+                    "free block not aligned to its size",
+                )?;
+                let header = unsafe { slab_header(class, addr) };
+                ensure(
+                    addr >= header as usize + header_blocks(class) * block_size,
+                    addr,
+                    "free block overlaps its slab header",
+                )?;
+                let header = unsafe { &*header };
+                ensure(
+                    header.class == class && header.live_blocks < blocks_per_slab(class),
+                    addr,
+                    "free block outside of a slab of its size class",
+                )?;
+                length += 1;
+            }
+
+            let head_addr = head.map_or(0, |node| node as *const ListNode as usize);
+            ensure(
+                length == self.free_blocks[class],
+                head_addr,
+                "free list length does not match free_blocks",
+            )?;
+            ensure(
+                length + self.live_blocks[class] == self.slabs[class] * blocks_per_slab(class),
+                head_addr,
+                "free and live blocks do not add up to the slabs",
+            )?;
+        }
+        Ok(())
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(idx) => match allocator.list_heads[idx].take() {
-                Some(node) => {
-                    allocator.list_heads[idx] = node.next.take();
-                    node as *mut ListNode as *mut u8
-                }
-                None => {
-                    let size = BLOCK_SIZES[idx];
-                    let align = size;
-                    let new_layout = Layout::from_size_align(size, align).unwrap();
-                    allocator.fallback_alloc(new_layout)
+        let (ptr, size) = match list_index(&layout) {
+            Some(idx) => {
+                if allocator.list_heads[idx].is_none() && !allocator.refill(idx) {
+                    return ptr::null_mut();
                 }
-            },
-            None => allocator.fallback_alloc(layout),
+                let ptr = allocator.pop_block(idx).unwrap();
+                unsafe { (*slab_header(idx, ptr as usize)).live_blocks += 1 };
+                allocator.live_blocks[idx] += 1;
+                (ptr, BLOCK_SIZES[idx])
+            }
+            None => (allocator.fallback_alloc(layout), layout.size()),
+        };
+
+        if !ptr.is_null() {
+            allocator.usage.record_alloc(size);
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
         match list_index(&layout) {
             Some(idx) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[idx].take(),
-                };
-                let new_node_ptr = ptr as *mut ListNode;
-                unsafe {
-                    new_node_ptr.write(new_node);
-                    allocator.list_heads[idx] = Some(&mut *new_node_ptr)
-                };
+                unsafe { allocator.push_block(idx, ptr as usize) };
+                let header = unsafe { &mut *slab_header(idx, ptr as usize) };
+                header.live_blocks -= 1;
+                let slab_is_free = header.live_blocks == 0;
+                if slab_is_free && allocator.free_blocks[idx] > blocks_per_slab(idx) {
+                    allocator.release_slab(idx, header as *mut SlabHeader as usize);
+                }
+                allocator.live_blocks[idx] -= 1;
+                allocator.usage.record_dealloc(BLOCK_SIZES[idx]);
             }
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
                 unsafe {
                     allocator.fallback_allocator.deallocate(ptr, layout);
                 }
+                allocator.usage.record_dealloc(layout.size());
             }
         }
     }
+
+    /// Keeps the block if the new size falls into the same size class, and shrinks large blocks in
+    /// place. Anything else is copied.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let Ok(new_layout) = Layout::from_size_align(new_size, layout.align()) else {
+            return ptr::null_mut();
+        };
+        let class = list_index(&layout);
+        if class.is_some() && class == list_index(&new_layout) {
+            return ptr;
+        }
+        if class.is_none()
+            && list_index(&new_layout).is_none()
+            && new_size <= layout.size()
+            && unsafe {
+                self.lock()
+                    .shrink_fallback(ptr as usize, layout.size(), new_size)
+            }
+        {
+            return ptr;
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod slab {
+    use super::*;
+
+    const TEST_HEAP_SIZE: usize = 4 * SLAB_SIZE;
+
+    #[repr(align(4096))]
+    struct TestHeap([u8; TEST_HEAP_SIZE]);
+
+    /// Creates an allocator on `test_heap`, which cannot grow.
+    fn new_allocator(test_heap: &mut TestHeap) -> Locked<FixedSizeBlockAllocator> {
+        let allocator = Locked::new(FixedSizeBlockAllocator::new());
+        unsafe {
+            allocator
+                .lock()
+                .init(test_heap.0.as_mut_ptr() as usize, TEST_HEAP_SIZE)
+        };
+        allocator
+    }
+
+    fn slab_count(allocator: &Locked<FixedSizeBlockAllocator>) -> usize {
+        allocator.lock().slabs.iter().sum()
+    }
+
+    #[test_case]
+    fn refill_splits_a_whole_slab() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr_1 = unsafe { allocator.alloc(layout) };
+        let ptr_2 = unsafe { allocator.alloc(layout) };
+
+        // the first block holds the header, the others are handed out in address order
+        assert_eq!(ptr_1 as usize % SLAB_SIZE, 64);
+        assert_eq!(ptr_2 as usize, ptr_1 as usize + 64);
+        assert_eq!(slab_count(&allocator), 1);
+        let class = list_index(&layout).unwrap();
+        assert_eq!(allocator.lock().free_blocks[class], SLAB_SIZE / 64 - 3);
+        assert_eq!(allocator.lock().fallback_allocator.used(), SLAB_SIZE);
+    }
+
+    #[test_case]
+    fn size_classes_use_separate_slabs() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let small = unsafe { allocator.alloc(Layout::new::<u64>()) };
+        let large = unsafe { allocator.alloc(Layout::from_size_align(512, 8).unwrap()) };
+        assert_eq!(slab_count(&allocator), 2);
+        assert_ne!(
+            small as usize & !(SLAB_SIZE - 1),
+            large as usize & !(SLAB_SIZE - 1)
+        );
+    }
+
+    #[test_case]
+    fn header_records_class_and_live_blocks() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(512, 8).unwrap();
+        let class = list_index(&layout).unwrap();
+        let ptrs = [(); 3].map(|_| unsafe { allocator.alloc(layout) });
+        unsafe { allocator.dealloc(ptrs[1], layout) };
+
+        let header = unsafe { &*slab_header(class, ptrs[2] as usize) };
+        assert_eq!(header as *const SlabHeader as usize, ptrs[0] as usize - 512);
+        assert_eq!(header.class, class);
+        assert_eq!(header.live_blocks, 2);
+    }
+
+    #[test_case]
+    fn large_blocks_use_larger_slabs() {
+        for class in 0..NUM_SIZE_CLASSES {
+            assert!(slab_size(class).is_power_of_two());
+            assert!(blocks_per_slab(class) >= MIN_BLOCKS_PER_SLAB - 1);
+        }
+        assert_eq!(slab_size(0), SLAB_SIZE);
+        assert_eq!(slab_size(NUM_SIZE_CLASSES - 1), 16 * 1024);
+    }
+
+    #[test_case]
+    fn last_free_slab_of_a_class_is_kept() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(512, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        unsafe { allocator.dealloc(ptr, layout) };
+
+        assert_eq!(slab_count(&allocator), 1);
+        assert_eq!(allocator.lock().fallback_allocator.used(), SLAB_SIZE);
+    }
+
+    #[test_case]
+    fn free_slab_returns_to_fallback() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let allocator = new_allocator(&mut test_heap);
+
+        // 512 byte blocks fill a slab with seven allocations, next to the header
+        let layout = Layout::from_size_align(512, 8).unwrap();
+        let class = list_index(&layout).unwrap();
+        let ptrs = [(); 14].map(|_| unsafe { allocator.alloc(layout) });
+        assert_eq!(slab_count(&allocator), 2);
+
+        // free the first slab while the second one is still in use
+        for &ptr in &ptrs[..7] {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        assert_eq!(slab_count(&allocator), 2, "only free slab of its class");
+
+        // the second slab becomes free while the first one is still free too
+        for &ptr in &ptrs[7..] {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        assert_eq!(slab_count(&allocator), 1);
+        assert_eq!(allocator.lock().free_blocks[class], blocks_per_slab(class));
+        assert_eq!(allocator.lock().fallback_allocator.used(), SLAB_SIZE);
+    }
+
+    #[test_case]
+    fn released_blocks_leave_the_free_list() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(512, 8).unwrap();
+        let ptrs = [(); 14].map(|_| unsafe { allocator.alloc(layout) });
+        for ptr in ptrs {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+
+        // the second slab was released, so only blocks of the first one remain
+        let mut allocator = allocator.lock();
+        let remaining = ptrs[0] as usize & !(SLAB_SIZE - 1);
+        let class = list_index(&layout).unwrap();
+        while let Some(block) = allocator.pop_block(class) {
+            assert_eq!(block as usize & !(SLAB_SIZE - 1), remaining);
+        }
+    }
+}
+
+#[cfg(test)]
+mod realloc {
+    use super::*;
+
+    const TEST_HEAP_SIZE: usize = 4 * SLAB_SIZE;
+
+    #[repr(align(4096))]
+    struct TestHeap([u8; TEST_HEAP_SIZE]);
+
+    fn new_allocator(test_heap: &mut TestHeap) -> Locked<FixedSizeBlockAllocator> {
+        let allocator = Locked::new(FixedSizeBlockAllocator::new());
+        unsafe {
+            allocator
+                .lock()
+                .init(test_heap.0.as_mut_ptr() as usize, TEST_HEAP_SIZE)
+        };
+        allocator
+    }
+
+    #[test_case]
+    fn same_size_class_keeps_block() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(20, 4).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        let grown = unsafe { allocator.realloc(ptr, layout, 32) };
+        assert_eq!(grown, ptr);
+
+        let grown_layout = Layout::from_size_align(32, 4).unwrap();
+        let shrunk = unsafe { allocator.realloc(grown, grown_layout, 17) };
+        assert_eq!(shrunk, ptr);
+        assert_eq!(allocator.lock().stats().allocated_bytes, 32);
+    }
+
+    #[test_case]
+    fn other_size_class_copies() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        unsafe { ptr.write_bytes(0xab, 16) };
+
+        let new_ptr = unsafe { allocator.realloc(ptr, layout, 100) };
+        assert_ne!(new_ptr, ptr);
+        for offset in 0..16 {
+            assert_eq!(unsafe { new_ptr.add(offset).read() }, 0xab);
+        }
+
+        let size_classes = allocator.lock().size_classes().unwrap();
+        assert_eq!(size_classes[list_index(&layout).unwrap()].live_blocks, 0);
+        assert_eq!(
+            size_classes[list_index(&Layout::new::<[u8; 100]>()).unwrap()].live_blocks,
+            1
+        );
+    }
+
+    #[test_case]
+    fn large_block_shrinks_in_place() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(3 * SLAB_SIZE, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        let shrunk = unsafe { allocator.realloc(ptr, layout, SLAB_SIZE) };
+        assert_eq!(shrunk, ptr);
+        assert_eq!(allocator.lock().fallback_allocator.used(), SLAB_SIZE);
+
+        // the freed tail can be allocated again
+        let tail_layout = Layout::from_size_align(2 * SLAB_SIZE, 8).unwrap();
+        let tail = unsafe { allocator.alloc(tail_layout) };
+        assert_eq!(tail as usize, ptr as usize + SLAB_SIZE);
+
+        let shrunk_layout = Layout::from_size_align(SLAB_SIZE, 8).unwrap();
+        unsafe {
+            allocator.dealloc(tail, tail_layout);
+            allocator.dealloc(shrunk, shrunk_layout);
+        }
+        assert_eq!(allocator.lock().fallback_allocator.used(), 0);
+        assert_eq!(allocator.lock().stats().allocated_bytes, 0);
+    }
 }
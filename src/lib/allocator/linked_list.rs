@@ -1,8 +1,17 @@
+use super::{
+    stats::{AllocatorStats, HeapStats, Usage},
+    validate::{Corruption, Validate, ensure},
+};
 use core::{
     alloc::{GlobalAlloc, Layout},
-    mem, ptr,
+    fmt, mem,
+    ops::Range,
+    ptr,
 };
 
+/// Maximum number of discontiguous regions which can be added to a LinkedListAllocator.
+const MAX_HEAP_REGIONS: usize = 8;
+
 pub struct ListNode {
     size: usize,
     next: Option<&'static mut ListNode>,
@@ -26,9 +35,10 @@ impl ListNode {
 
     /// Given an allocation layout, determines the size to allocate such that:
     /// 1. the request is met, and
-    /// 2. the allocated region will fit a ListNode when eventually freed.
+    /// 2. the allocated region will fit a ListNode when eventually freed, and
+    /// 3. the region after the allocated region stays aligned for a ListNode.
     fn alloc_size(layout: Layout) -> usize {
-        layout.size().max(mem::size_of::<Self>())
+        super::align_up(layout.size(), mem::align_of::<Self>()).max(mem::size_of::<Self>())
     }
 
     /// Given an allocation layout, determines the alignment such that:
@@ -45,17 +55,24 @@ impl ListNode {
 
     /// Attempts to allocate a memory region in the ListNode.
     /// Returns a UsableRegion with the allocated region.
+    /// If the start of the ListNode is not aligned, the allocated region is moved up to the next
+    /// aligned address, and the returned UsableRegion will also contain a padding_region.
     /// If there is extra space in the ListNode, the returned UsableRegion will also contain an
     /// excess_region.
     ///
     /// Returns Err if:
-    /// 1. the ListNode is too small for the requested size, or
+    /// 1. the ListNode is too small for the requested size (including padding), or
     /// 2. the leftover size is too small to fit a new ListNode.
     fn try_allocate(&self, size: usize, align: usize) -> Result<UsableRegion, ()> {
-        let alloc_start = self.start_addr();
-
-        // This assertion is already done by LinkedListAllocator.add_free_region()
-        assert_eq!(super::align_up(alloc_start, align), alloc_start);
+        let node_start = self.start_addr();
+
+        let mut alloc_start = super::align_up(node_start, align);
+        if alloc_start != node_start && alloc_start - node_start < mem::size_of::<Self>() {
+            // The padding must be able to fit a ListNode too, so move up to the next aligned
+            // address which leaves enough space
+            let min_start = node_start.checked_add(mem::size_of::<Self>()).ok_or(())?;
+            alloc_start = super::align_up(min_start, align);
+        }
 
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
@@ -68,6 +85,11 @@ impl ListNode {
             size,
         };
 
+        let padding_region = (alloc_start > node_start).then_some(MemRegion {
+            start: node_start,
+            size: alloc_start - node_start,
+        });
+
         let remaining_size = self.end_addr() - alloc_end;
         if remaining_size > 0 {
             if remaining_size < mem::size_of::<Self>() {
@@ -80,12 +102,14 @@ impl ListNode {
             };
             return Ok(UsableRegion {
                 alloc_region,
+                padding_region,
                 excess_region: Some(excess_region),
             });
         }
 
         Ok(UsableRegion {
             alloc_region,
+            padding_region,
             excess_region: None,
         })
     }
@@ -94,6 +118,7 @@ impl ListNode {
 #[derive(Debug, PartialEq, Eq)]
 struct UsableRegion {
     alloc_region: MemRegion,
+    padding_region: Option<MemRegion>,
     excess_region: Option<MemRegion>,
 }
 
@@ -103,21 +128,83 @@ struct MemRegion {
     size: usize,
 }
 
+/// Decides which free region an allocation is carved from, when several are large enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitPolicy {
+    /// The region with the lowest address
+    First,
+    /// The smallest region, leaving large regions intact for large allocations
+    Best,
+    /// The largest region, leaving the biggest leftover region behind
+    Worst,
+    /// The first region after where the previous allocation ended, wrapping around to the start
+    Next,
+}
+
+/// Why LinkedListAllocator refused to free a pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeallocError {
+    /// The freed block is not completely inside a region added with init or add_free_region
+    OutsideHeap,
+    /// The freed block overlaps memory which is already free, e.g. because of a double free
+    OverlapsFreeRegion,
+}
+
+impl fmt::Display for DeallocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeallocError::OutsideHeap => write!(f, "pointer is outside of the heap"),
+            DeallocError::OverlapsFreeRegion => {
+                write!(f, "memory is already free (double free?)")
+            }
+        }
+    }
+}
+
 pub struct LinkedListAllocator {
     // Dummy ListNode whose `next` points to the first node
     head: ListNode,
+    policy: FitPolicy,
+    /// Address where the previous allocation ended, used by FitPolicy::Next
+    next_fit_cursor: usize,
+    /// End of the region passed to init, where the heap grows when it runs out of memory
+    heap_end: usize,
+    /// Set by enable_growth
+    growable: bool,
+    /// Every region which was added to the heap, merged where they touch
+    heap_regions: [Option<Range<usize>>; MAX_HEAP_REGIONS],
+    usage: Usage,
 }
 
 /// A heap allocator that writes ListNodes into the heap, forming a Linked List.
 ///
-/// TODO: Does not merge freed blocks for now.
+/// The LL is sorted by address, so that freed blocks can be merged with their neighbours.
 impl LinkedListAllocator {
     pub const fn new() -> Self {
+        Self::with_policy(FitPolicy::First)
+    }
+
+    pub const fn with_policy(policy: FitPolicy) -> Self {
         LinkedListAllocator {
             head: ListNode::new(0),
+            policy,
+            next_fit_cursor: 0,
+            heap_end: 0,
+            growable: false,
+            heap_regions: [const { None }; MAX_HEAP_REGIONS],
+            usage: Usage::new(),
         }
     }
 
+    pub fn policy(&self) -> FitPolicy {
+        self.policy
+    }
+
+    /// Changes the placement policy used by future allocations.
+    pub fn set_policy(&mut self, policy: FitPolicy) {
+        self.policy = policy;
+    }
+
     /// Initialise the allocator with the given heap bounds.
     ///
     /// Unsafe because the caller must guarantee the given heap bounds are valid and that the heap
@@ -126,13 +213,99 @@ impl LinkedListAllocator {
         unsafe {
             self.add_free_region(heap_start, heap_size);
         }
+        self.heap_end = heap_start + heap_size;
+    }
+
+    /// Lets the allocator map more pages after the end of its heap when it runs out of memory.
+    ///
+    /// Only the global allocator may do this, as the pages after the heap are reserved for it. Any
+    /// other instance would hand out memory which the global allocator considers free.
+    pub fn enable_growth(&mut self) {
+        self.growable = true;
+    }
+
+    /// Maps more pages after the end of the heap and adds them as a free region, large enough to
+    /// fit an allocation with the given size and alignment on its own.
+    ///
+    /// Returns false if the heap cannot grow, or may not.
+    fn grow(&mut self, size: usize, align: usize) -> bool {
+        if !self.growable {
+            return false;
+        }
+        // Leave room for padding in front of the allocation
+        let growth = super::align_up(size + align + mem::size_of::<ListNode>(), 4096);
+        if !super::grow_heap(self.heap_end, growth) {
+            return false;
+        }
+        unsafe { self.add_free_region(self.heap_end, growth) };
+        self.heap_end += growth;
+        true
+    }
+
+    /// Adds the memory region at addr with the given size to the heap, and makes it available
+    /// for allocation.
+    ///
+    /// The region does not need to be adjacent to the rest of the heap, but at most
+    /// MAX_HEAP_REGIONS discontiguous regions can be added.
+    ///
+    /// Unsafe because the caller must make sure addr is a valid address which can be written over
+    /// and is not used by anything else.
+    pub unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        self.add_heap_region(addr..addr + size);
+        unsafe { self.free_region(addr, size) };
+    }
+
+    /// Remembers that range belongs to the heap, merging it with the regions it overlaps or
+    /// touches.
+    fn add_heap_region(&mut self, range: Range<usize>) {
+        let mut merged = range;
+        for slot in self.heap_regions.iter_mut() {
+            if let Some(region) = slot.take_if(|r| r.start <= merged.end && merged.start <= r.end) {
+                merged = region.start.min(merged.start)..region.end.max(merged.end);
+            }
+        }
+
+        let slot = self
+            .heap_regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .expect("LinkedListAllocator supports at most MAX_HEAP_REGIONS heap regions");
+        *slot = Some(merged);
+    }
+
+    /// Checks that the block at addr with the given size can be freed, i.e. that it lies within
+    /// the heap and is not free already.
+    fn check_dealloc(&self, addr: usize, size: usize) -> Result<(), DeallocError> {
+        let end = addr.checked_add(size).ok_or(DeallocError::OutsideHeap)?;
+        if !self.in_heap(addr, end) {
+            return Err(DeallocError::OutsideHeap);
+        }
+
+        let overlaps_free = self
+            .nodes()
+            .any(|node| node.start_addr() < end && addr < node.end_addr());
+        if overlaps_free {
+            return Err(DeallocError::OverlapsFreeRegion);
+        }
+        Ok(())
+    }
+
+    /// Whether `start..end` lies completely inside one of the heap regions.
+    fn in_heap(&self, start: usize, end: usize) -> bool {
+        self.heap_regions
+            .iter()
+            .flatten()
+            .any(|r| r.start <= start && end <= r.end)
     }
 
-    /// Adds a ListNode to the front of the LL, representing addr and size.
+    /// Adds a ListNode representing addr and size to the LL, keeping the LL sorted by address.
+    ///
+    /// If the region is directly adjacent to the free region before or after it, they are merged
+    /// into a single ListNode.
     ///
     /// Unsafe because the caller must make sure addr is a valid address on the heap which can be
     /// written over.
-    pub unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+    unsafe fn free_region(&mut self, addr: usize, size: usize) {
         assert_eq!(
             super::align_up(addr, mem::align_of::<ListNode>()),
             addr,
@@ -143,35 +316,187 @@ impl LinkedListAllocator {
             "size must be big enough to fit a ListNode"
         );
 
+        // find the last node which starts before addr
+        let head_addr = self.head.start_addr();
+        let mut prev = &mut self.head;
+        while prev
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() < addr)
+        {
+            prev = prev.next.as_mut().unwrap();
+        }
+
         let mut node = ListNode::new(size);
-        // put new node in front of the LL
-        node.next = self.head.next.take();
+        node.next = prev.next.take();
+
+        // merge with the next node
+        if node
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() == addr + size)
+        {
+            let next = node.next.take().unwrap();
+            node.size += next.size;
+            node.next = next.next.take();
+        }
+
+        // merge with the previous node (the dummy head does not represent a region)
+        if prev.start_addr() != head_addr && prev.end_addr() == addr {
+            prev.size += node.size;
+            prev.next = node.next.take();
+            return;
+        }
 
         // create a ListNode pointer from the given addr (which is in the heap)
         let node_ptr = addr as *mut ListNode;
         unsafe {
             // write node (currently on stack) into heap ptr
             node_ptr.write(node);
-            // set head.next to the heap copy of node
-            self.head.next = Some(&mut *node_ptr);
+            // link the heap copy of node after prev
+            prev.next = Some(&mut *node_ptr);
         }
     }
 
     /// Looks for a free region with the given size and alignment and removes it from the list.
     ///
-    /// Returns a UsableRegion which may contain an excess_region.
-    fn extract_first_suitable_region(&mut self, size: usize, align: usize) -> Option<UsableRegion> {
+    /// Which region is chosen when several are suitable depends on the FitPolicy.
+    /// Returns a UsableRegion which may contain a padding_region and an excess_region.
+    fn extract_suitable_region(&mut self, size: usize, align: usize) -> Option<UsableRegion> {
+        let chosen = self.choose_region(size, align)?;
+        let usable_region = self.remove_region(chosen, size, align);
+        self.next_fit_cursor = usable_region.alloc_region.start + usable_region.alloc_region.size;
+        Some(usable_region)
+    }
+
+    /// Returns the start address of the free region the FitPolicy picks for the given size and
+    /// alignment.
+    fn choose_region(&self, size: usize, align: usize) -> Option<usize> {
+        let mut suitable = self
+            .nodes()
+            .filter(|node| node.try_allocate(size, align).is_ok());
+        let chosen = match self.policy {
+            FitPolicy::First => suitable.next(),
+            FitPolicy::Best => suitable.min_by_key(|node| node.size),
+            // max_by_key would return the last of several equally large regions, but ties should
+            // go to the lowest address like in the other policies
+            FitPolicy::Worst => {
+                suitable.fold(None, |largest: Option<&ListNode>, node| match largest {
+                    Some(largest) if largest.size >= node.size => Some(largest),
+                    _ => Some(node),
+                })
+            }
+            FitPolicy::Next => {
+                let mut first = None;
+                let mut after_cursor = None;
+                for node in suitable {
+                    first.get_or_insert(node);
+                    if node.start_addr() >= self.next_fit_cursor {
+                        after_cursor = Some(node);
+                        break;
+                    }
+                }
+                after_cursor.or(first)
+            }
+        };
+        chosen.map(ListNode::start_addr)
+    }
+
+    /// Removes the node starting at addr from the list and allocates from it.
+    ///
+    /// The node must exist and be suitable for the given size and alignment.
+    fn remove_region(&mut self, addr: usize, size: usize, align: usize) -> UsableRegion {
         let mut curr = &mut self.head;
-        while let Some(ref mut node) = curr.next {
-            if let Ok(usable_region) = node.try_allocate(size, align) {
-                let next = node.next.take();
-                curr.next = next;
-                return Some(usable_region);
-            } else {
-                curr = curr.next.as_mut().unwrap();
+        while curr.next.as_ref().unwrap().start_addr() != addr {
+            curr = curr.next.as_mut().unwrap();
+        }
+        let node = curr.next.take().unwrap();
+        curr.next = node.next.take();
+        node.try_allocate(size, align).unwrap()
+    }
+
+    /// Resizes the allocated block at addr from old_size to new_size bytes without moving it.
+    ///
+    /// Shrinking gives the tail back to the LL. Growing takes the start of the free region right
+    /// after the block. Returns false if the block cannot be resized in place, e.g. because the
+    /// tail is too small to hold a ListNode or the next region is not free.
+    ///
+    /// Unsafe because the block must be allocated, and both sizes must come from alloc_size.
+    unsafe fn resize_in_place(&mut self, addr: usize, old_size: usize, new_size: usize) -> bool {
+        let old_end = addr + old_size;
+        if new_size <= old_size {
+            let tail = old_size - new_size;
+            if tail > 0 && tail < mem::size_of::<ListNode>() {
+                return false;
+            }
+            if tail > 0 {
+                unsafe { self.free_region(addr + new_size, tail) };
+            }
+        } else {
+            let growth = new_size - old_size;
+            let next_is_suitable = self
+                .nodes()
+                .any(|node| node.start_addr() == old_end && node.try_allocate(growth, 1).is_ok());
+            if !next_is_suitable {
+                return false;
             }
+            let UsableRegion { excess_region, .. } = self.remove_region(old_end, growth, 1);
+            if let Some(MemRegion { start, size }) = excess_region {
+                unsafe { self.free_region(start, size) };
+            }
+        }
+
+        self.usage.record_realloc(old_size, new_size);
+        true
+    }
+
+    /// Iterates over the free regions, sorted by address.
+    fn nodes(&self) -> impl Iterator<Item = &ListNode> {
+        core::iter::successors(self.head.next.as_deref(), |node| node.next.as_deref())
+    }
+}
+
+impl AllocatorStats for LinkedListAllocator {
+    fn stats(&self) -> HeapStats {
+        let free = self.nodes().map(|node| node.size).sum();
+        let largest = self.nodes().map(|node| node.size).max().unwrap_or(0);
+        self.usage.stats(free, Some(largest))
+    }
+}
+
+impl Validate for LinkedListAllocator {
+    /// Checks that the free regions are inside the heap, can hold a ListNode, and are sorted by
+    /// address without overlapping or touching (touching regions must have been merged).
+    fn validate(&self) -> Result<(), Corruption> {
+        let mut prev_end = None;
+        for node in self.nodes() {
+            let addr = node.start_addr();
+            ensure(
+                node.size >= mem::size_of::<ListNode>(),
+                addr,
+                "free region too small for a ListNode",
+            )?;
+            ensure(
+                addr % mem::align_of::<ListNode>() == 0,
+                addr,
+                "free region not aligned for a ListNode",
+            )?;
+            ensure(
+                self.in_heap(addr, node.end_addr()),
+                addr,
+                "free region outside of the heap",
+            )?;
+            if let Some(prev_end) = prev_end {
+                ensure(
+                    prev_end <= addr,
+                    addr,
+                    "free regions unsorted or overlapping",
+                )?;
+                ensure(prev_end != addr, addr, "adjacent free regions not merged")?;
+            }
+            prev_end = Some(node.end_addr());
         }
-        None
+        Ok(())
     }
 }
 
@@ -182,34 +507,75 @@ unsafe impl GlobalAlloc for super::Locked<LinkedListAllocator> {
         let mut allocator = self.lock();
         let Some(UsableRegion {
             alloc_region,
+            padding_region,
             excess_region,
-        }) = allocator.extract_first_suitable_region(size, align)
+        }) = allocator.extract_suitable_region(size, align).or_else(|| {
+            allocator
+                .grow(size, align)
+                .then(|| allocator.extract_suitable_region(size, align))
+                .flatten()
+        })
         else {
             return ptr::null_mut();
         };
 
-        // If there is an excess_region, add it back to the LL
-        if let Some(MemRegion { start, size }) = excess_region {
+        // If there is a padding_region or an excess_region, add them back to the LL
+        for MemRegion { start, size } in padding_region.into_iter().chain(excess_region) {
             unsafe {
-                allocator.add_free_region(start, size);
+                allocator.free_region(start, size);
             }
         }
 
+        allocator.usage.record_alloc(alloc_region.size);
         alloc_region.start as *mut u8
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        // TODO: check that ptr is within the original inited region
+        let size = ListNode::alloc_size(layout);
+        let mut allocator = self.lock();
+        if let Err(error) = allocator.check_dealloc(ptr as usize, size) {
+            if cfg!(debug_assertions) {
+                panic!("invalid dealloc of {size} bytes at {ptr:?}: {error}");
+            }
+            // Leaking the block is better than corrupting the LL
+            return;
+        }
+
+        allocator.usage.record_dealloc(size);
         unsafe {
+            allocator.free_region(ptr as usize, size);
+        }
+    }
+
+    /// Resizes in place if possible, so that e.g. a growing Vec is not copied every time.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let Ok(new_layout) = Layout::from_size_align(new_size, layout.align()) else {
+            return ptr::null_mut();
+        };
+        let old_size = ListNode::alloc_size(layout);
+        let resized = unsafe {
             self.lock()
-                .add_free_region(ptr as usize, ListNode::alloc_size(layout));
+                .resize_in_place(ptr as usize, old_size, ListNode::alloc_size(new_layout))
+        };
+        if resized {
+            return ptr;
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
         }
+        new_ptr
     }
 }
 
 #[cfg(test)]
 mod list_node {
     use super::*;
+    use crate::allocator::align_up;
 
     #[test_case]
     fn assert_size_and_align() {
@@ -259,6 +625,7 @@ mod list_node {
                     start: node.start_addr(),
                     size: 16,
                 },
+                padding_region: None,
                 excess_region: None,
             };
             assert_eq!(node.try_allocate(16, 8), Ok(expected));
@@ -276,6 +643,7 @@ mod list_node {
                     start: node.start_addr(),
                     size: 16,
                 },
+                padding_region: None,
                 excess_region: Some(MemRegion {
                     start: node.start_addr() + 16,
                     size: 16, // 32 - 16 = 16 extra bytes (exactly 1 ListNode)
@@ -296,6 +664,7 @@ mod list_node {
                     start: node.start_addr(),
                     size: 16,
                 },
+                padding_region: None,
                 excess_region: Some(MemRegion {
                     start: node.start_addr() + 16,
                     size: 48, // 64 - 16 = 48 extra bytes
@@ -303,6 +672,64 @@ mod list_node {
             };
             assert_eq!(node.try_allocate(16, 8), Ok(expected));
         }
+
+        #[test_case]
+        fn unaligned_start_is_padded() {
+            let mut test_heap = test_utils::AlignedBuffer::new();
+            // 32 bytes before the next 64 byte boundary
+            let node_start = align_up(test_heap.start_addr(), 64) + 32;
+            let node = unsafe { test_utils::write_list_node(node_start, 128) };
+
+            let expected = UsableRegion {
+                alloc_region: MemRegion {
+                    start: node_start + 32,
+                    size: 16,
+                },
+                padding_region: Some(MemRegion {
+                    start: node_start,
+                    size: 32,
+                }),
+                excess_region: Some(MemRegion {
+                    start: node_start + 48,
+                    size: 80, // 128 - 32 - 16 = 80 extra bytes
+                }),
+            };
+            assert_eq!(node.try_allocate(16, 64), Ok(expected));
+        }
+
+        #[test_case]
+        fn padding_too_small_for_list_node() {
+            let mut test_heap = test_utils::AlignedBuffer::new();
+            // 8 bytes before the next 16 byte boundary, which cannot fit a ListNode
+            let node_start = test_heap.start_addr() + 8;
+            let node = unsafe { test_utils::write_list_node(node_start, 64) };
+
+            let expected = UsableRegion {
+                alloc_region: MemRegion {
+                    start: node_start + 24,
+                    size: 16,
+                },
+                padding_region: Some(MemRegion {
+                    start: node_start,
+                    size: 24,
+                }),
+                excess_region: Some(MemRegion {
+                    start: node_start + 40,
+                    size: 24,
+                }),
+            };
+            assert_eq!(node.try_allocate(16, 16), Ok(expected));
+        }
+
+        #[test_case]
+        fn padding_does_not_fit() {
+            let mut test_heap = test_utils::AlignedBuffer::new();
+            let node_start = align_up(test_heap.start_addr(), 64) + 32;
+            let node = unsafe { test_utils::write_list_node(node_start, 64) };
+
+            // 32 bytes of padding + 48 bytes is more than the node holds
+            assert_eq!(node.try_allocate(48, 64), Err(()));
+        }
     }
 }
 
@@ -352,7 +779,7 @@ mod linked_list_allocator {
             let mut ll_allocator = test_utils::new_ll_allocator_with_five_blocks(addrs, sizes);
 
             let mut curr = &mut ll_allocator.head;
-            for &addr in addrs.iter().rev() {
+            for &addr in addrs.iter() {
                 let node = curr.next.as_mut().unwrap();
                 assert_eq!(node.size, 32);
                 assert_eq!(node.start_addr(), addr);
@@ -389,13 +816,13 @@ mod linked_list_allocator {
         // }
     }
 
-    mod extract_first_suitable_region {
+    mod extract_suitable_region {
         use super::*;
 
         #[test_case]
         fn empty_allocator() {
             let mut ll_allocator = LinkedListAllocator::new();
-            assert!(ll_allocator.extract_first_suitable_region(32, 8).is_none());
+            assert!(ll_allocator.extract_suitable_region(32, 8).is_none());
         }
 
         #[test_case]
@@ -405,11 +832,7 @@ mod linked_list_allocator {
             let mut ll_allocator = LinkedListAllocator::new();
             unsafe { ll_allocator.init(test_heap.start_addr(), 4096) };
 
-            assert!(
-                ll_allocator
-                    .extract_first_suitable_region(8192, 8)
-                    .is_none()
-            );
+            assert!(ll_allocator.extract_suitable_region(8192, 8).is_none());
         }
 
         #[test_case]
@@ -427,7 +850,7 @@ mod linked_list_allocator {
             let mut ll_allocator = test_utils::new_ll_allocator_with_five_blocks(addrs, sizes);
 
             assert!(
-                ll_allocator.extract_first_suitable_region(33, 8).is_none(),
+                ll_allocator.extract_suitable_region(33, 8).is_none(),
                 "requesting 33 bytes when each block is 32 should return None"
             );
         }
@@ -436,12 +859,13 @@ mod linked_list_allocator {
         fn multiple_list_nodes_one_has_sufficient_space() {
             let mut test_heap = test_utils::AlignedBuffer::new();
             let heap_start = test_heap.start_addr();
+            // spaced out so that the 64 byte block is not merged with the one after it
             let addrs = [
                 heap_start,
-                heap_start + 64,
                 heap_start + 128,
-                heap_start + 192,
                 heap_start + 256,
+                heap_start + 384,
+                heap_start + 512,
             ];
             let sizes = [32, 32, 32, 64, 32];
             let mut ll_allocator = test_utils::new_ll_allocator_with_five_blocks(addrs, sizes);
@@ -449,8 +873,9 @@ mod linked_list_allocator {
             let UsableRegion {
                 alloc_region,
                 excess_region,
+                ..
             } = ll_allocator
-                .extract_first_suitable_region(48, 8)
+                .extract_suitable_region(48, 8)
                 .expect("requesting 33 bytes when each block is 32 should return Some");
 
             // check that indeed the 4th block was allocated
@@ -534,16 +959,494 @@ mod alloc_dealloc {
 
         let one_kib_layout = Layout::new::<test_utils::OneKiB>();
 
-        // Reuse the 2 2KiB chunks as 4 1KiB chunks
+        // Reuse the 2 2KiB chunks (merged back into one 4KiB region) as 4 1KiB chunks
         let ptr_3 = unsafe { locked_allocator.alloc(one_kib_layout) };
         let ptr_4 = unsafe { locked_allocator.alloc(one_kib_layout) };
         let ptr_5 = unsafe { locked_allocator.alloc(one_kib_layout) };
         let ptr_6 = unsafe { locked_allocator.alloc(one_kib_layout) };
 
-        assert_eq!(ptr_3 as usize, heap_start + 2048);
-        assert_eq!(ptr_4 as usize, heap_start + 3072);
-        assert_eq!(ptr_5 as usize, heap_start);
-        assert_eq!(ptr_6 as usize, heap_start + 1024);
+        assert_eq!(ptr_3 as usize, heap_start);
+        assert_eq!(ptr_4 as usize, heap_start + 1024);
+        assert_eq!(ptr_5 as usize, heap_start + 2048);
+        assert_eq!(ptr_6 as usize, heap_start + 3072);
+    }
+
+    #[test_case]
+    fn full_alloc_dealloc_cycle_leaves_single_region() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let heap_start = test_heap.start_addr();
+
+        let locked_allocator = Locked::new(LinkedListAllocator::new());
+
+        unsafe { locked_allocator.lock().init(heap_start, 4096) };
+
+        let layouts = [
+            Layout::new::<usize>(),
+            Layout::new::<test_utils::OneKiB>(),
+            Layout::new::<[u64; 5]>(),
+            Layout::new::<test_utils::OneKiB>(),
+            Layout::new::<u8>(),
+        ];
+        let mut ptrs = [ptr::null_mut(); 5];
+        for (ptr, &layout) in ptrs.iter_mut().zip(layouts.iter()) {
+            *ptr = unsafe { locked_allocator.alloc(layout) };
+            assert!(!ptr.is_null());
+        }
+
+        // free in an order which needs merging on both sides
+        for idx in [1, 3, 0, 4, 2] {
+            unsafe { locked_allocator.dealloc(ptrs[idx], layouts[idx]) };
+        }
+
+        let allocator = locked_allocator.lock();
+        let node = allocator
+            .head
+            .next
+            .as_ref()
+            .expect("heap should have a free region");
+        assert_eq!(node.start_addr(), heap_start);
+        assert_eq!(node.size, 4096);
+        assert!(node.next.is_none(), "heap should be a single free region");
+    }
+
+    #[test_case]
+    fn adjacent_regions_are_merged() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let heap_start = test_heap.start_addr();
+
+        let mut ll_allocator = LinkedListAllocator::new();
+        unsafe {
+            ll_allocator.add_free_region(heap_start + 64, 32);
+            ll_allocator.add_free_region(heap_start, 32);
+            ll_allocator.add_free_region(heap_start + 32, 32);
+        }
+
+        let node = ll_allocator.head.next.as_ref().unwrap();
+        assert_eq!(node.start_addr(), heap_start);
+        assert_eq!(node.size, 96);
+        assert!(node.next.is_none());
+    }
+}
+
+#[cfg(test)]
+mod realloc {
+    use super::*;
+    use crate::allocator::Locked;
+
+    fn new_allocator(test_heap: &mut test_utils::AlignedBuffer) -> Locked<LinkedListAllocator> {
+        let locked_allocator = Locked::new(LinkedListAllocator::new());
+        unsafe { locked_allocator.lock().init(test_heap.start_addr(), 4096) };
+        locked_allocator
+    }
+
+    #[test_case]
+    fn grows_into_next_free_region() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let heap_start = test_heap.start_addr();
+        let locked_allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = unsafe { locked_allocator.alloc(layout) };
+        let new_ptr = unsafe { locked_allocator.realloc(ptr, layout, 256) };
+        assert_eq!(new_ptr, ptr);
+
+        let allocator = locked_allocator.lock();
+        let node = allocator.head.next.as_ref().unwrap();
+        assert_eq!(node.start_addr(), heap_start + 256);
+        assert_eq!(node.size, 4096 - 256);
+        assert_eq!(allocator.stats().allocated_bytes, 256);
+        assert_eq!(allocator.stats().live_allocations, 1);
+    }
+
+    #[test_case]
+    fn shrinks_in_place() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let heap_start = test_heap.start_addr();
+        let locked_allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::new::<test_utils::OneKiB>();
+        let ptr = unsafe { locked_allocator.alloc(layout) };
+        let new_ptr = unsafe { locked_allocator.realloc(ptr, layout, 64) };
+        assert_eq!(new_ptr, ptr);
+
+        // the tail is merged with the rest of the heap
+        let allocator = locked_allocator.lock();
+        let node = allocator.head.next.as_ref().unwrap();
+        assert_eq!(node.start_addr(), heap_start + 64);
+        assert_eq!(node.size, 4096 - 64);
+        assert!(node.next.is_none());
+        assert_eq!(allocator.stats().allocated_bytes, 64);
+    }
+
+    #[test_case]
+    fn moves_if_next_region_is_used() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let locked_allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = unsafe { locked_allocator.alloc(layout) };
+        let _next = unsafe { locked_allocator.alloc(layout) };
+        unsafe { ptr.write_bytes(0xab, 64) };
+
+        let new_ptr = unsafe { locked_allocator.realloc(ptr, layout, 256) };
+        assert_ne!(new_ptr, ptr);
+        for offset in 0..64 {
+            assert_eq!(unsafe { new_ptr.add(offset).read() }, 0xab);
+        }
+        assert_eq!(locked_allocator.lock().stats().live_allocations, 2);
+    }
+
+    #[test_case]
+    fn moves_if_tail_cannot_hold_list_node() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let locked_allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = unsafe { locked_allocator.alloc(layout) };
+        let new_ptr = unsafe { locked_allocator.realloc(ptr, layout, 16) };
+        assert_ne!(new_ptr, ptr);
+        assert_eq!(locked_allocator.lock().stats().allocated_bytes, 16);
+    }
+}
+
+#[cfg(test)]
+mod stats {
+    use super::*;
+    use crate::allocator::Locked;
+
+    #[test_case]
+    fn stats_follow_alloc_and_dealloc() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let heap_start = test_heap.start_addr();
+
+        let locked_allocator = Locked::new(LinkedListAllocator::new());
+        unsafe { locked_allocator.lock().init(heap_start, 4096) };
+
+        let small = Layout::new::<usize>();
+        let large = Layout::new::<test_utils::OneKiB>();
+        let ptr_1 = unsafe { locked_allocator.alloc(small) };
+        let ptr_2 = unsafe { locked_allocator.alloc(large) };
+
+        // small allocations are rounded up to fit a ListNode
+        let stats = locked_allocator.lock().stats();
+        assert_eq!(stats.allocated_bytes, 16 + 1024);
+        assert_eq!(stats.live_allocations, 2);
+        assert_eq!(stats.free_bytes, 4096 - 16 - 1024);
+        assert_eq!(stats.largest_free_block, Some(4096 - 16 - 1024));
+
+        unsafe { locked_allocator.dealloc(ptr_2, large) };
+        let stats = locked_allocator.lock().stats();
+        assert_eq!(stats.allocated_bytes, 16);
+        assert_eq!(stats.peak_allocated_bytes, 16 + 1024);
+        assert_eq!(stats.live_allocations, 1);
+
+        unsafe { locked_allocator.dealloc(ptr_1, small) };
+        let stats = locked_allocator.lock().stats();
+        assert_eq!(stats.allocated_bytes, 0);
+        assert_eq!(stats.free_bytes, 4096);
+        assert_eq!(stats.largest_free_block, Some(4096));
+    }
+
+    #[test_case]
+    fn largest_free_block_with_fragmentation() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let heap_start = test_heap.start_addr();
+        let addrs = [
+            heap_start,
+            heap_start + 128,
+            heap_start + 256,
+            heap_start + 384,
+            heap_start + 512,
+        ];
+        let sizes = [32, 64, 96, 16, 48];
+        let ll_allocator = test_utils::new_ll_allocator_with_five_blocks(addrs, sizes);
+
+        let stats = ll_allocator.stats();
+        assert_eq!(stats.free_bytes, 32 + 64 + 96 + 16 + 48);
+        assert_eq!(stats.largest_free_block, Some(96));
+    }
+}
+
+#[cfg(test)]
+mod fit_policy {
+    use super::*;
+
+    /// Creates an allocator with free regions of 64, 128, 48, 192 and 96 bytes, in that order.
+    ///
+    /// Returns the allocator and the start addresses of the regions.
+    fn new_allocator(
+        test_heap: &mut test_utils::AlignedBuffer,
+        policy: FitPolicy,
+    ) -> (LinkedListAllocator, [usize; 5]) {
+        let heap_start = test_heap.start_addr();
+        // spaced out so that no regions are merged
+        let addrs = [
+            heap_start,
+            heap_start + 256,
+            heap_start + 512,
+            heap_start + 768,
+            heap_start + 1024,
+        ];
+        let sizes = [64, 128, 48, 192, 96];
+        let mut ll_allocator = test_utils::new_ll_allocator_with_five_blocks(addrs, sizes);
+        ll_allocator.set_policy(policy);
+        (ll_allocator, addrs)
+    }
+
+    fn extract_start(ll_allocator: &mut LinkedListAllocator, size: usize) -> usize {
+        ll_allocator
+            .extract_suitable_region(size, 8)
+            .expect("a suitable region should exist")
+            .alloc_region
+            .start
+    }
+
+    #[test_case]
+    fn default_is_first_fit() {
+        assert_eq!(LinkedListAllocator::new().policy(), FitPolicy::First);
+    }
+
+    #[test_case]
+    fn first_fit_picks_lowest_address() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let (mut ll_allocator, addrs) = new_allocator(&mut test_heap, FitPolicy::First);
+        assert_eq!(extract_start(&mut ll_allocator, 48), addrs[0]);
+    }
+
+    #[test_case]
+    fn best_fit_picks_smallest_region() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let (mut ll_allocator, addrs) = new_allocator(&mut test_heap, FitPolicy::Best);
+        assert_eq!(extract_start(&mut ll_allocator, 48), addrs[2]);
+        assert_eq!(extract_start(&mut ll_allocator, 48), addrs[0]);
+    }
+
+    #[test_case]
+    fn worst_fit_picks_largest_region() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let (mut ll_allocator, addrs) = new_allocator(&mut test_heap, FitPolicy::Worst);
+        assert_eq!(extract_start(&mut ll_allocator, 48), addrs[3]);
+        // the 192 byte region is gone, so the 128 byte region is now the largest
+        assert_eq!(extract_start(&mut ll_allocator, 48), addrs[1]);
+    }
+
+    #[test_case]
+    fn next_fit_resumes_after_previous_allocation() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let (mut ll_allocator, addrs) = new_allocator(&mut test_heap, FitPolicy::Next);
+        assert_eq!(extract_start(&mut ll_allocator, 64), addrs[0]);
+
+        // freeing the first region again does not make next fit go back to it
+        unsafe { ll_allocator.add_free_region(addrs[0], 64) };
+        assert_eq!(extract_start(&mut ll_allocator, 64), addrs[1]);
+    }
+
+    #[test_case]
+    fn next_fit_wraps_around() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let (mut ll_allocator, addrs) = new_allocator(&mut test_heap, FitPolicy::Next);
+        assert_eq!(extract_start(&mut ll_allocator, 192), addrs[3]);
+
+        // only the 96 byte region is after the previous allocation, and it is too small
+        assert_eq!(extract_start(&mut ll_allocator, 128), addrs[1]);
+    }
+
+    #[test_case]
+    fn policy_can_be_changed() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let (mut ll_allocator, addrs) = new_allocator(&mut test_heap, FitPolicy::First);
+        ll_allocator.set_policy(FitPolicy::Best);
+        assert_eq!(extract_start(&mut ll_allocator, 96), addrs[4]);
+    }
+}
+
+#[cfg(test)]
+mod dealloc_validation {
+    use super::*;
+    use crate::allocator::Locked;
+
+    /// Creates an allocator with two discontiguous 1KiB regions, with a 1KiB gap between them.
+    ///
+    /// Returns the allocator and the start addresses of the regions.
+    fn new_allocator(
+        test_heap: &mut test_utils::AlignedBuffer,
+    ) -> (Locked<LinkedListAllocator>, [usize; 2]) {
+        let heap_start = test_heap.start_addr();
+        let addrs = [heap_start, heap_start + 2048];
+        let locked_allocator = Locked::new(LinkedListAllocator::new());
+        for addr in addrs {
+            unsafe { locked_allocator.lock().add_free_region(addr, 1024) };
+        }
+        (locked_allocator, addrs)
+    }
+
+    #[test_case]
+    fn discontiguous_regions_are_remembered() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let (locked_allocator, addrs) = new_allocator(&mut test_heap);
+        let regions = locked_allocator.lock().heap_regions.clone();
+        assert_eq!(regions[0], Some(addrs[0]..addrs[0] + 1024));
+        assert_eq!(regions[1], Some(addrs[1]..addrs[1] + 1024));
+        assert!(regions[2..].iter().all(Option::is_none));
+    }
+
+    #[test_case]
+    fn adjacent_regions_are_remembered_as_one() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let (locked_allocator, addrs) = new_allocator(&mut test_heap);
+        // fill the gap between the two regions
+        unsafe {
+            locked_allocator
+                .lock()
+                .add_free_region(addrs[0] + 1024, 1024)
+        };
+
+        let regions = locked_allocator.lock().heap_regions.clone();
+        assert_eq!(regions.iter().flatten().count(), 1);
+        assert!(regions.contains(&Some(addrs[0]..addrs[1] + 1024)));
+    }
+
+    #[test_case]
+    fn allocations_from_every_region_can_be_freed() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let (locked_allocator, addrs) = new_allocator(&mut test_heap);
+
+        let layout = Layout::new::<test_utils::OneKiB>();
+        let ptr_1 = unsafe { locked_allocator.alloc(layout) };
+        let ptr_2 = unsafe { locked_allocator.alloc(layout) };
+        assert_eq!(ptr_1 as usize, addrs[0]);
+        assert_eq!(ptr_2 as usize, addrs[1]);
+
+        let allocator = locked_allocator.lock();
+        assert_eq!(allocator.check_dealloc(addrs[0], 1024), Ok(()));
+        assert_eq!(allocator.check_dealloc(addrs[1], 1024), Ok(()));
+    }
+
+    #[test_case]
+    fn pointer_outside_heap_is_rejected() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let (locked_allocator, addrs) = new_allocator(&mut test_heap);
+        let allocator = locked_allocator.lock();
+
+        // in the gap between the regions
+        assert_eq!(
+            allocator.check_dealloc(addrs[0] + 1024, 16),
+            Err(DeallocError::OutsideHeap)
+        );
+        // after the last region
+        assert_eq!(
+            allocator.check_dealloc(addrs[1] + 1024, 16),
+            Err(DeallocError::OutsideHeap)
+        );
+    }
+
+    #[test_case]
+    fn block_straddling_region_end_is_rejected() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let (locked_allocator, addrs) = new_allocator(&mut test_heap);
+        let layout = Layout::new::<test_utils::OneKiB>();
+        unsafe { locked_allocator.alloc(layout) };
+
+        assert_eq!(
+            locked_allocator.lock().check_dealloc(addrs[0] + 512, 1024),
+            Err(DeallocError::OutsideHeap)
+        );
+    }
+
+    #[test_case]
+    fn double_free_is_rejected() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let (locked_allocator, addrs) = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = unsafe { locked_allocator.alloc(layout) };
+        assert_eq!(ptr as usize, addrs[0]);
+        unsafe { locked_allocator.dealloc(ptr, layout) };
+
+        assert_eq!(
+            locked_allocator.lock().check_dealloc(ptr as usize, 64),
+            Err(DeallocError::OverlapsFreeRegion)
+        );
+    }
+
+    #[test_case]
+    fn never_allocated_memory_is_rejected() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let (locked_allocator, addrs) = new_allocator(&mut test_heap);
+        assert_eq!(
+            locked_allocator.lock().check_dealloc(addrs[1] + 64, 64),
+            Err(DeallocError::OverlapsFreeRegion)
+        );
+    }
+}
+
+#[cfg(test)]
+mod alignment {
+    use super::*;
+    use crate::allocator::Locked;
+
+    /// Allocates from a heap whose start is only aligned to 8 bytes, checks that the allocation is
+    /// aligned and that the padding in front of it was returned to the LL.
+    fn check_aligned_alloc(align: usize) {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        // deliberately misaligned for anything above 8 bytes
+        let heap_start = test_heap.start_addr() + 8;
+        let heap_size = test_utils::AlignedBuffer::SIZE - 16;
+
+        let locked_allocator = Locked::new(LinkedListAllocator::new());
+        unsafe { locked_allocator.lock().init(heap_start, heap_size) };
+
+        let layout = Layout::from_size_align(32, align).unwrap();
+        let ptr = unsafe { locked_allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % align, 0, "allocation should be aligned");
+
+        let first_start = locked_allocator
+            .lock()
+            .head
+            .next
+            .as_ref()
+            .unwrap()
+            .start_addr();
+        assert_eq!(first_start, heap_start, "padding should be a free region");
+
+        unsafe { locked_allocator.dealloc(ptr, layout) };
+
+        let allocator = locked_allocator.lock();
+        let node = allocator.head.next.as_ref().unwrap();
+        assert_eq!(node.start_addr(), heap_start);
+        assert_eq!(node.size, heap_size);
+        assert!(node.next.is_none(), "heap should be a single free region");
+    }
+
+    #[test_case]
+    fn align_16() {
+        check_aligned_alloc(16);
+    }
+
+    #[test_case]
+    fn align_64() {
+        check_aligned_alloc(64);
+    }
+
+    #[test_case]
+    fn align_4096() {
+        check_aligned_alloc(4096);
+    }
+
+    #[test_case]
+    fn odd_sizes_keep_free_regions_aligned() {
+        let mut test_heap = test_utils::AlignedBuffer::new();
+        let heap_start = test_heap.start_addr();
+
+        let locked_allocator = Locked::new(LinkedListAllocator::new());
+        unsafe { locked_allocator.lock().init(heap_start, 4096) };
+
+        let layout = Layout::from_size_align(41, 8).unwrap();
+        let ptr_1 = unsafe { locked_allocator.alloc(layout) };
+        let ptr_2 = unsafe { locked_allocator.alloc(layout) };
+        assert_eq!(ptr_1 as usize, heap_start);
+        assert_eq!(ptr_2 as usize, heap_start + 48);
     }
 }
 
@@ -552,12 +1455,15 @@ mod test_utils {
     use super::*;
     use crate::allocator::align_up;
 
+    /// Large enough to hold a page-aligned 4KiB allocation wherever the buffer starts.
     #[repr(align(16))]
-    pub struct AlignedBuffer([u8; 4096]);
+    pub struct AlignedBuffer([u8; AlignedBuffer::SIZE]);
 
     impl AlignedBuffer {
+        pub const SIZE: usize = 8192;
+
         pub fn new() -> Self {
-            AlignedBuffer([0; 4096])
+            AlignedBuffer([0; AlignedBuffer::SIZE])
         }
 
         pub fn start_addr(&mut self) -> usize {
@@ -570,6 +1476,17 @@ mod test_utils {
     #[allow(dead_code)]
     pub struct TwoKiB([u8; 2048]);
 
+    /// Writes a ListNode of the given size at addr, outside of any LinkedListAllocator.
+    ///
+    /// Unsafe because addr must be aligned for ListNode and valid for writes.
+    pub unsafe fn write_list_node(addr: usize, size: usize) -> &'static ListNode {
+        let node_ptr = addr as *mut ListNode;
+        unsafe {
+            node_ptr.write(ListNode::new(size));
+            &*node_ptr
+        }
+    }
+
     /// Creates a LinkedListAllocator with 5 blocks, whose starting addresses and sizes are as
     /// specified in the arrays.
     pub fn new_ll_allocator_with_five_blocks(
@@ -0,0 +1,165 @@
+use super::{heap_limit, meminfo, stats::MemInfo};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    ops::Deref,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use spin::Mutex;
+
+/// Called when the heap is out of memory, to give some memory back before the allocation is
+/// retried. Returns the number of bytes it released, either to the heap or to the frame allocator
+/// (which lets the heap grow again).
+///
+/// Callbacks run in the middle of an allocation, so they must not allocate themselves.
+pub type PressureCallback = fn() -> usize;
+
+/// Number of memory pressure callbacks which can be registered at the same time.
+pub const MAX_PRESSURE_CALLBACKS: usize = 8;
+
+static PRESSURE_CALLBACKS: Mutex<[Option<PressureCallback>; MAX_PRESSURE_CALLBACKS]> =
+    Mutex::new([None; MAX_PRESSURE_CALLBACKS]);
+
+/// Bytes released by the callbacks during the last allocation which ran out of memory.
+static LAST_RELEASED: AtomicUsize = AtomicUsize::new(0);
+
+/// Returned by register_pressure_callback if MAX_PRESSURE_CALLBACKS are registered already.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyCallbacks;
+
+/// Registers a callback which runs whenever an allocation fails, e.g.
+/// `register_pressure_callback(|| INODE_CACHE.reclaim() * 4096)`.
+pub fn register_pressure_callback(callback: PressureCallback) -> Result<(), TooManyCallbacks> {
+    let mut callbacks = PRESSURE_CALLBACKS.lock();
+    let slot = callbacks
+        .iter_mut()
+        .find(|c| c.is_none())
+        .ok_or(TooManyCallbacks)?;
+    *slot = Some(callback);
+    Ok(())
+}
+
+/// Unregisters a callback registered with register_pressure_callback, e.g. when the cache it
+/// reclaims from goes away. Returns false if it was not registered.
+pub fn unregister_pressure_callback(callback: PressureCallback) -> bool {
+    let mut callbacks = PRESSURE_CALLBACKS.lock();
+    let slot = callbacks
+        .iter_mut()
+        .find(|c| c.is_some_and(|c| ptr::fn_addr_eq(c, callback)));
+    match slot {
+        Some(slot) => {
+            *slot = None;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Runs every registered memory pressure callback, returning the total number of bytes released.
+pub fn relieve_pressure() -> usize {
+    // copied out, so that a callback may register another one without deadlocking
+    let callbacks = *PRESSURE_CALLBACKS.lock();
+    let released = callbacks.iter().flatten().map(|callback| callback()).sum();
+    LAST_RELEASED.store(released, Ordering::Relaxed);
+    released
+}
+
+/// What the kernel knows about an allocation which could not be satisfied.
+#[derive(Debug, Clone, Copy)]
+pub struct AllocErrorReport {
+    pub layout: Layout,
+    /// Bytes the memory pressure callbacks released before giving up
+    pub released: usize,
+    pub meminfo: MemInfo,
+}
+
+impl AllocErrorReport {
+    /// Collects the report for a failed allocation of `layout`.
+    ///
+    /// Locks the global allocator, so it must not be called from inside of it.
+    pub fn new(layout: Layout) -> Self {
+        AllocErrorReport {
+            layout,
+            released: LAST_RELEASED.load(Ordering::Relaxed),
+            meminfo: meminfo(),
+        }
+    }
+}
+
+impl fmt::Display for AllocErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "out of memory: failed to allocate {} bytes with align {}",
+            self.layout.size(),
+            self.layout.align()
+        )?;
+        writeln!(f, "heap limit:         {} bytes", heap_limit())?;
+        writeln!(f, "released on demand: {} bytes", self.released)?;
+        write!(f, "{}", self.meminfo)
+    }
+}
+
+/// A GlobalAlloc wrapper which runs the memory pressure callbacks when the wrapped allocator runs
+/// out of memory, and retries the allocation if they released anything.
+///
+/// The allocators grow the heap on their own, so by the time this kicks in the heap is either at
+/// its limit or out of frames.
+pub struct OomHandler<A> {
+    inner: A,
+}
+
+impl<A> OomHandler<A> {
+    pub const fn new(inner: A) -> Self {
+        OomHandler { inner }
+    }
+
+    /// Calls `alloc` again after relieving memory pressure, if it returned null.
+    fn retry(&self, ptr: *mut u8, alloc: impl FnOnce() -> *mut u8) -> *mut u8 {
+        if ptr.is_null() && relieve_pressure() > 0 {
+            alloc()
+        } else {
+            ptr
+        }
+    }
+}
+
+impl<A> Deref for OomHandler<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for OomHandler<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc(layout) };
+        self.retry(ptr, || unsafe { self.inner.alloc(layout) })
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc_zeroed(layout) };
+        self.retry(ptr, || unsafe { self.inner.alloc_zeroed(layout) })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.inner.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // on failure, the old allocation is left untouched, so it can simply be tried again
+        let new_ptr = unsafe { self.inner.realloc(ptr, layout, new_size) };
+        self.retry(new_ptr, || unsafe {
+            self.inner.realloc(ptr, layout, new_size)
+        })
+    }
+}
+
+/// Called for allocations which cannot fail (e.g. `Box::new`) once the global allocator returns
+/// null, instead of the generic "memory allocation failed" panic.
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!("{}", AllocErrorReport::new(layout))
+}
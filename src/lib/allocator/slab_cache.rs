@@ -0,0 +1,351 @@
+use crate::memory::{KERNEL_MEMORY, KernelMemory};
+use core::{
+    fmt,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+};
+
+/// Every slab is a single page, taken from the kernel frame allocator.
+const SLAB_SIZE: usize = 4096;
+
+/// Written at the start of every slab, followed by the objects.
+struct SlabHeader {
+    next: Option<NonNull<SlabHeader>>,
+    free_objects: Option<NonNull<FreeObject>>,
+    live_objects: usize,
+    frame: PhysFrame,
+}
+
+/// Written into every free object of a slab.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+struct CacheState {
+    /// Slabs with at least one free object
+    partial: Option<NonNull<SlabHeader>>,
+    /// Slabs without free objects
+    full: Option<NonNull<SlabHeader>>,
+    slabs: usize,
+    live_objects: usize,
+}
+
+// The slabs are only ever accessed while holding the lock around CacheState
+unsafe impl Send for CacheState {}
+
+/// Usage numbers of a single SlabCache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabCacheStats {
+    pub name: &'static str,
+    /// Bytes taken up by every object, including padding
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub live_objects: usize,
+    pub free_objects: usize,
+}
+
+impl fmt::Display for SlabCacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} live, {} free objects of {} bytes in {} slabs",
+            self.name, self.live_objects, self.free_objects, self.object_size, self.slabs
+        )
+    }
+}
+
+/// A cache of objects of type T, for kernel objects which are allocated often.
+///
+/// Objects are packed into page-sized slabs at their exact size, instead of being rounded up to a
+/// power of two like in FixedSizeBlockAllocator. Slabs are taken from the kernel frame allocator
+/// (so `memory::init_kernel_memory` must have been called) and accessed through the physical
+/// memory mapping, so the cache does not depend on the heap.
+///
+/// New objects are initialised by the constructor, and the destructor runs right before an object
+/// is dropped. Slabs whose objects are all free stay in the cache until `reclaim` is called.
+///
+/// Slabs are taken while holding KERNEL_MEMORY, so never use a SlabCache while holding that lock.
+pub struct SlabCache<T> {
+    name: &'static str,
+    constructor: fn() -> T,
+    destructor: Option<fn(&mut T)>,
+    state: Mutex<CacheState>,
+    _marker: PhantomData<T>,
+}
+
+// Objects are handed out to whichever thread allocates them
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+const fn align_up(addr: usize, align: usize) -> usize {
+    addr.div_ceil(align) * align
+}
+
+impl<T> SlabCache<T> {
+    const OBJECT_ALIGN: usize = max(mem::align_of::<T>(), mem::align_of::<FreeObject>());
+
+    /// Distance between two objects in a slab, large enough to hold a FreeObject when free.
+    const OBJECT_SIZE: usize = align_up(
+        max(mem::size_of::<T>(), mem::size_of::<FreeObject>()),
+        Self::OBJECT_ALIGN,
+    );
+
+    /// Offset of the first object in a slab, right after the SlabHeader.
+    const FIRST_OBJECT: usize = align_up(mem::size_of::<SlabHeader>(), Self::OBJECT_ALIGN);
+
+    pub const OBJECTS_PER_SLAB: usize =
+        SLAB_SIZE.saturating_sub(Self::FIRST_OBJECT) / Self::OBJECT_SIZE;
+
+    /// Creates an empty cache, named `name` in its stats.
+    ///
+    /// Panics if a T does not fit into a slab, which is a compile error for caches in statics.
+    pub const fn new(name: &'static str, constructor: fn() -> T) -> Self {
+        assert!(
+            Self::OBJECTS_PER_SLAB > 0,
+            "SlabCache objects must fit into a single page"
+        );
+        SlabCache {
+            name,
+            constructor,
+            destructor: None,
+            state: Mutex::new(CacheState {
+                partial: None,
+                full: None,
+                slabs: 0,
+                live_objects: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets a function which is called on every object right before it is dropped.
+    pub const fn with_destructor(mut self, destructor: fn(&mut T)) -> Self {
+        self.destructor = Some(destructor);
+        self
+    }
+
+    /// Allocates an object, initialised by the constructor.
+    ///
+    /// Returns None if no frame is left for a new slab.
+    pub fn alloc(&self) -> Option<SlabBox<'_, T>> {
+        let object = self.alloc_object()?;
+        // the constructor may allocate from this cache itself, so it runs without the lock
+        unsafe { object.write((self.constructor)()) };
+        Some(SlabBox {
+            cache: self,
+            object,
+        })
+    }
+
+    pub fn stats(&self) -> SlabCacheStats {
+        let state = self.state.lock();
+        SlabCacheStats {
+            name: self.name,
+            object_size: Self::OBJECT_SIZE,
+            objects_per_slab: Self::OBJECTS_PER_SLAB,
+            slabs: state.slabs,
+            live_objects: state.live_objects,
+            free_objects: state.slabs * Self::OBJECTS_PER_SLAB - state.live_objects,
+        }
+    }
+
+    /// Gives every slab without live objects back to the frame allocator.
+    ///
+    /// Returns the number of pages which were freed.
+    pub fn reclaim(&self) -> usize {
+        let mut state = self.state.lock();
+        let mut released = 0;
+
+        let mut list = state.partial.take();
+        while let Some(slab) = list {
+            let slab = slab.as_ptr();
+            unsafe {
+                list = (*slab).next;
+                if (*slab).live_objects == 0 {
+                    deallocate_page((*slab).frame);
+                    released += 1;
+                } else {
+                    (*slab).next = state.partial;
+                    state.partial = NonNull::new(slab);
+                }
+            }
+        }
+
+        state.slabs -= released;
+        released
+    }
+
+    /// Takes a free object from a slab, without initialising it.
+    fn alloc_object(&self) -> Option<NonNull<T>> {
+        let mut state = self.state.lock();
+        if state.partial.is_none() {
+            state.partial = Some(Self::new_slab()?);
+            state.slabs += 1;
+        }
+
+        let slab = state.partial.unwrap();
+        let object = unsafe {
+            let header = slab.as_ptr();
+            let object = (*header).free_objects.unwrap();
+            (*header).free_objects = (*object.as_ptr()).next;
+            (*header).live_objects += 1;
+
+            if (*header).free_objects.is_none() {
+                state.partial = (*header).next;
+                (*header).next = state.full;
+                state.full = Some(slab);
+            }
+            object
+        };
+
+        state.live_objects += 1;
+        Some(object.cast())
+    }
+
+    /// Returns an object to its slab, after it was dropped.
+    ///
+    /// Unsafe because the object must have been allocated from this cache and not be used anymore.
+    unsafe fn free_object(&self, object: NonNull<T>) {
+        let mut state = self.state.lock();
+        let slab_addr = object.as_ptr() as usize & !(SLAB_SIZE - 1);
+        let slab = NonNull::new(slab_addr as *mut SlabHeader).unwrap();
+
+        unsafe {
+            let header = slab.as_ptr();
+            if (*header).free_objects.is_none() {
+                // the slab was full, so it has to move to the partial list
+                unlink(&mut state.full, slab);
+                (*header).next = state.partial;
+                state.partial = Some(slab);
+            }
+
+            let free_object = object.cast::<FreeObject>();
+            free_object.write(FreeObject {
+                next: (*header).free_objects,
+            });
+            (*header).free_objects = Some(free_object);
+            (*header).live_objects -= 1;
+        }
+
+        state.live_objects -= 1;
+    }
+
+    /// Takes a page for a new slab and threads all of its objects onto its free list.
+    fn new_slab() -> Option<NonNull<SlabHeader>> {
+        let (frame, addr) = allocate_page()?;
+
+        // pushed in reverse, so that objects are handed out in address order
+        let mut free_objects = None;
+        for idx in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let object: *mut FreeObject =
+                (addr + (Self::FIRST_OBJECT + idx * Self::OBJECT_SIZE) as u64).as_mut_ptr();
+            unsafe {
+                object.write(FreeObject { next: free_objects });
+            }
+            free_objects = NonNull::new(object);
+        }
+
+        let header: *mut SlabHeader = addr.as_mut_ptr();
+        unsafe {
+            header.write(SlabHeader {
+                next: None,
+                free_objects,
+                live_objects: 0,
+                frame,
+            });
+        }
+        NonNull::new(header)
+    }
+}
+
+impl<T> Drop for SlabCache<T> {
+    /// No SlabBox can outlive the cache, so every slab is empty and can be freed.
+    fn drop(&mut self) {
+        self.reclaim();
+    }
+}
+
+/// Removes `slab` from the list starting at `head`.
+///
+/// Unsafe because every slab on the list must be valid.
+unsafe fn unlink(head: &mut Option<NonNull<SlabHeader>>, slab: NonNull<SlabHeader>) {
+    let mut curr = head;
+    while let Some(node) = *curr {
+        if node == slab {
+            *curr = unsafe { (*node.as_ptr()).next };
+            return;
+        }
+        curr = unsafe { &mut (*node.as_ptr()).next };
+    }
+}
+
+/// Takes a frame from the kernel frame allocator, returning it with its address in the physical
+/// memory mapping.
+fn allocate_page() -> Option<(PhysFrame, VirtAddr)> {
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    let KernelMemory {
+        mapper,
+        frame_allocator,
+    } = kernel_memory.as_mut()?;
+    let frame = frame_allocator.allocate_frame()?;
+    Some((frame, mapper.phys_offset() + frame.start_address().as_u64()))
+}
+
+/// Gives a frame taken by allocate_page back to the kernel frame allocator.
+///
+/// Unsafe because the frame must not be used anymore.
+unsafe fn deallocate_page(frame: PhysFrame) {
+    if let Some(kernel_memory) = KERNEL_MEMORY.lock().as_mut() {
+        unsafe { kernel_memory.frame_allocator.deallocate_frame(frame) };
+    }
+}
+
+/// An object allocated from a SlabCache, which is returned to it when dropped.
+pub struct SlabBox<'a, T> {
+    cache: &'a SlabCache<T>,
+    object: NonNull<T>,
+}
+
+impl<T> Deref for SlabBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabBox<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for SlabBox<'_, T> {
+    fn drop(&mut self) {
+        if let Some(destructor) = self.cache.destructor {
+            destructor(unsafe { self.object.as_mut() });
+        }
+        unsafe {
+            ptr::drop_in_place(self.object.as_ptr());
+            self.cache.free_object(self.object);
+        }
+    }
+}
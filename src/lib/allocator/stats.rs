@@ -0,0 +1,158 @@
+use core::{fmt, ops::Deref};
+
+/// Usage numbers of a heap allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes handed out to live allocations, including any rounding up done by the allocator
+    pub allocated_bytes: usize,
+    /// Bytes which can still be allocated without growing the heap
+    pub free_bytes: usize,
+    /// Highest `allocated_bytes` seen so far
+    pub peak_allocated_bytes: usize,
+    /// Number of allocations which have not been freed yet
+    pub live_allocations: usize,
+    /// Largest allocation which can succeed without growing the heap, if the allocator knows it
+    pub largest_free_block: Option<usize>,
+}
+
+/// Usage numbers of a single size class of FixedSizeBlockAllocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    /// Blocks of this size which are currently allocated
+    pub live_blocks: usize,
+    /// Blocks of this size waiting on the free list
+    pub free_blocks: usize,
+}
+
+/// Usage per size class of an allocator, in order of increasing block size.
+///
+/// Stored inline rather than on the heap, so that it can be taken while the allocator is locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeClasses {
+    classes: [SizeClassStats; SizeClasses::MAX],
+    len: usize,
+}
+
+impl SizeClasses {
+    /// Most size classes an allocator can report.
+    pub const MAX: usize = 16;
+
+    /// Collects the stats of every size class.
+    ///
+    /// Panics if there are more than `MAX` size classes.
+    pub fn new(classes: impl IntoIterator<Item = SizeClassStats>) -> Self {
+        let mut size_classes = SizeClasses {
+            classes: [SizeClassStats {
+                block_size: 0,
+                live_blocks: 0,
+                free_blocks: 0,
+            }; Self::MAX],
+            len: 0,
+        };
+        for class in classes {
+            assert!(size_classes.len < Self::MAX, "too many size classes");
+            size_classes.classes[size_classes.len] = class;
+            size_classes.len += 1;
+        }
+        size_classes
+    }
+}
+
+impl Deref for SizeClasses {
+    type Target = [SizeClassStats];
+
+    fn deref(&self) -> &[SizeClassStats] {
+        &self.classes[..self.len]
+    }
+}
+
+/// Implemented by every heap allocator, so that memory usage can be inspected whichever one is
+/// the global allocator.
+pub trait AllocatorStats {
+    fn stats(&self) -> HeapStats;
+
+    /// Usage per size class, for allocators which have them.
+    fn size_classes(&self) -> Option<SizeClasses> {
+        None
+    }
+}
+
+/// Bookkeeping shared by the allocators to implement AllocatorStats.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Usage {
+    allocated_bytes: usize,
+    peak_allocated_bytes: usize,
+    live_allocations: usize,
+}
+
+impl Usage {
+    pub(super) const fn new() -> Self {
+        Usage {
+            allocated_bytes: 0,
+            peak_allocated_bytes: 0,
+            live_allocations: 0,
+        }
+    }
+
+    pub(super) fn record_alloc(&mut self, size: usize) {
+        self.allocated_bytes += size;
+        self.peak_allocated_bytes = self.peak_allocated_bytes.max(self.allocated_bytes);
+        self.live_allocations += 1;
+    }
+
+    pub(super) fn record_dealloc(&mut self, size: usize) {
+        self.allocated_bytes = self.allocated_bytes.saturating_sub(size);
+        self.live_allocations = self.live_allocations.saturating_sub(1);
+    }
+
+    /// Records an allocation which was resized in place, so it is still a single allocation.
+    pub(super) fn record_realloc(&mut self, old_size: usize, new_size: usize) {
+        self.allocated_bytes = self.allocated_bytes.saturating_sub(old_size) + new_size;
+        self.peak_allocated_bytes = self.peak_allocated_bytes.max(self.allocated_bytes);
+    }
+
+    /// Fills in the fields of HeapStats which Usage keeps track of.
+    pub(super) fn stats(&self, free_bytes: usize, largest_free_block: Option<usize>) -> HeapStats {
+        HeapStats {
+            allocated_bytes: self.allocated_bytes,
+            free_bytes,
+            peak_allocated_bytes: self.peak_allocated_bytes,
+            live_allocations: self.live_allocations,
+            largest_free_block,
+        }
+    }
+}
+
+/// A snapshot of the global allocator's usage, printable with `println!` or `serial_println!`.
+#[derive(Debug, Clone, Copy)]
+pub struct MemInfo {
+    pub heap: HeapStats,
+    pub size_classes: Option<SizeClasses>,
+}
+
+impl fmt::Display for MemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let heap = &self.heap;
+        writeln!(f, "allocated:          {} bytes", heap.allocated_bytes)?;
+        writeln!(f, "peak allocated:     {} bytes", heap.peak_allocated_bytes)?;
+        writeln!(f, "free:               {} bytes", heap.free_bytes)?;
+        writeln!(f, "live allocations:   {}", heap.live_allocations)?;
+        match heap.largest_free_block {
+            Some(size) => writeln!(f, "largest free block: {size} bytes")?,
+            None => writeln!(f, "largest free block: unknown")?,
+        }
+
+        if let Some(size_classes) = &self.size_classes {
+            writeln!(f, "{:>10} {:>10} {:>10}", "block size", "live", "free")?;
+            for class in size_classes.iter() {
+                writeln!(
+                    f,
+                    "{:>10} {:>10} {:>10}",
+                    class.block_size, class.live_blocks, class.free_blocks
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
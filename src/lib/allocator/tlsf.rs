@@ -0,0 +1,701 @@
+use super::{
+    stats::{AllocatorStats, HeapStats, Usage},
+    validate::{Corruption, Validate, ensure},
+};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem,
+    ptr::{self, NonNull},
+};
+
+/// Every second level splits a first level range into 2^SL_INDEX_COUNT_LOG2 lists.
+const SL_INDEX_COUNT_LOG2: usize = 4;
+const SL_INDEX_COUNT: usize = 1 << SL_INDEX_COUNT_LOG2;
+
+/// Block sizes and addresses are multiples of this.
+const ALIGN_SIZE_LOG2: usize = 4;
+const ALIGN_SIZE: usize = 1 << ALIGN_SIZE_LOG2;
+
+/// Blocks smaller than SMALL_BLOCK_SIZE all live in the first level 0, whose second level lists
+/// are ALIGN_SIZE apart.
+const FL_INDEX_SHIFT: usize = SL_INDEX_COUNT_LOG2 + ALIGN_SIZE_LOG2;
+const SMALL_BLOCK_SIZE: usize = 1 << FL_INDEX_SHIFT;
+
+/// Blocks must be smaller than 2^FL_INDEX_MAX bytes, which no heap can reach.
+const FL_INDEX_MAX: usize = usize::BITS as usize - 1;
+const FL_INDEX_COUNT: usize = FL_INDEX_MAX - FL_INDEX_SHIFT + 1;
+
+// fl_bitmap has a bit per first level
+const _: () = assert!(FL_INDEX_COUNT <= u64::BITS as usize);
+
+/// Bytes of a BlockHeader which stay in use while the block is allocated. The free list links
+/// after them overlap the allocation.
+const HEADER_SIZE: usize = 2 * mem::size_of::<usize>();
+
+/// A free block must be able to hold a whole BlockHeader.
+const MIN_BLOCK_SIZE: usize = mem::size_of::<BlockHeader>();
+
+/// The sentinel at the end of the heap is read as a whole BlockHeader, like the blocks before it.
+const SENTINEL_SIZE: usize = mem::size_of::<BlockHeader>();
+
+/// Set in `BlockHeader::size` if the block is free. Sizes are multiples of ALIGN_SIZE, so the low
+/// bits are unused.
+const FREE_BIT: usize = 1;
+
+/// Written at the start of every block, free or not.
+///
+/// The heap ends in a sentinel block of size 0, so that every real block has a next block.
+#[repr(C)]
+struct BlockHeader {
+    /// The block right before this one in memory
+    prev_phys: Option<NonNull<BlockHeader>>,
+    /// Size of the block including its header, with FREE_BIT
+    size: usize,
+    /// Only valid while the block is free
+    next_free: Option<NonNull<BlockHeader>>,
+    /// Only valid while the block is free
+    prev_free: Option<NonNull<BlockHeader>>,
+}
+
+impl BlockHeader {
+    fn size(&self) -> usize {
+        self.size & !FREE_BIT
+    }
+
+    fn is_free(&self) -> bool {
+        self.size & FREE_BIT != 0
+    }
+
+    fn set_size(&mut self, size: usize) {
+        self.size = size | (self.size & FREE_BIT);
+    }
+
+    fn set_free(&mut self, free: bool) {
+        self.size = if free {
+            self.size | FREE_BIT
+        } else {
+            self.size & !FREE_BIT
+        };
+    }
+
+    /// The block right after this one in memory.
+    fn next_phys(&self) -> NonNull<BlockHeader> {
+        let addr = self as *const Self as usize + self.size();
+        NonNull::new(addr as *mut BlockHeader).unwrap()
+    }
+}
+
+/// Returns the first and second level index of the free list which holds blocks of size `size`.
+fn mapping_insert(size: usize) -> (usize, usize) {
+    if size < SMALL_BLOCK_SIZE {
+        (0, size / (SMALL_BLOCK_SIZE / SL_INDEX_COUNT))
+    } else {
+        let fl = size.ilog2() as usize;
+        let sl = (size >> (fl - SL_INDEX_COUNT_LOG2)) ^ SL_INDEX_COUNT;
+        (fl - FL_INDEX_SHIFT + 1, sl)
+    }
+}
+
+/// Returns the first free list whose blocks are all at least `size` bytes large.
+fn mapping_search(size: usize) -> (usize, usize) {
+    let size = if size >= SMALL_BLOCK_SIZE {
+        let round = (1 << (size.ilog2() as usize - SL_INDEX_COUNT_LOG2)) - 1;
+        size + round
+    } else {
+        size
+    };
+    mapping_insert(size)
+}
+
+/// A Two-Level Segregated Fit allocator, which allocates and frees in constant time.
+///
+/// Free blocks are kept in size-segregated lists. The first level splits sizes by powers of two,
+/// and the second level splits each of those ranges linearly. Bitmaps record which lists are
+/// non-empty, so a suitable list is found with a couple of bit scans instead of walking a list.
+/// Every block knows its physical neighbours, so freed blocks are merged with them right away.
+pub struct TlsfAllocator {
+    /// Bit `fl` is set if any list of first level `fl` is non-empty
+    fl_bitmap: u64,
+    /// Bit `sl` of `sl_bitmap[fl]` is set if `free_lists[fl][sl]` is non-empty
+    sl_bitmap: [u32; FL_INDEX_COUNT],
+    free_lists: [[Option<NonNull<BlockHeader>>; SL_INDEX_COUNT]; FL_INDEX_COUNT],
+    /// End of the heap, where it grows when it runs out of memory
+    heap_end: usize,
+    /// Set by enable_growth
+    growable: bool,
+    /// Sum of the sizes of all free blocks, excluding their headers
+    free_bytes: usize,
+    usage: Usage,
+}
+
+// The blocks are only accessed through the allocator, which is behind a Locked
+unsafe impl Send for TlsfAllocator {}
+
+impl TlsfAllocator {
+    pub const fn new() -> Self {
+        TlsfAllocator {
+            fl_bitmap: 0,
+            sl_bitmap: [0; FL_INDEX_COUNT],
+            free_lists: [[None; SL_INDEX_COUNT]; FL_INDEX_COUNT],
+            heap_end: 0,
+            growable: false,
+            free_bytes: 0,
+            usage: Usage::new(),
+        }
+    }
+
+    /// Initialise the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee the given heap bounds are valid and that the heap is unused. This
+    /// method can only be called once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        let start = super::align_up(heap_start, ALIGN_SIZE);
+        let end = (heap_start + heap_size) & !(ALIGN_SIZE - 1);
+        let sentinel = end - SENTINEL_SIZE;
+        assert!(
+            sentinel >= start + MIN_BLOCK_SIZE,
+            "heap is too small for a TlsfAllocator"
+        );
+
+        let block = start as *mut BlockHeader;
+        unsafe {
+            block.write(BlockHeader {
+                prev_phys: None,
+                size: sentinel - start,
+                next_free: None,
+                prev_free: None,
+            });
+            Self::write_sentinel(sentinel, NonNull::new(block));
+            self.free_block(NonNull::new_unchecked(block));
+        }
+        self.heap_end = end;
+    }
+
+    /// Lets the allocator map more pages after the end of its heap when it runs out of memory.
+    ///
+    /// Only the global allocator may do this, as the pages after the heap are reserved for it. Any
+    /// other instance would hand out memory which the global allocator considers free.
+    pub fn enable_growth(&mut self) {
+        self.growable = true;
+    }
+
+    /// Writes the size 0 block which marks the end of the heap.
+    ///
+    /// Unsafe because addr must be valid for writes of SENTINEL_SIZE bytes.
+    unsafe fn write_sentinel(addr: usize, prev_phys: Option<NonNull<BlockHeader>>) {
+        let sentinel = addr as *mut BlockHeader;
+        unsafe {
+            (&raw mut (*sentinel).prev_phys).write(prev_phys);
+            (&raw mut (*sentinel).size).write(0);
+        }
+    }
+
+    /// Maps more pages after the end of the heap and turns them into a free block, which
+    /// `find_suitable(block_size)` finds on its own.
+    ///
+    /// Returns false if the heap cannot grow, or may not.
+    fn grow(&mut self, block_size: usize) -> bool {
+        if !self.growable {
+            return false;
+        }
+        // mapping_search skips the free list block_size falls into, unless block_size is its lower
+        // bound, so the new block must be at least one free list width larger
+        let growth = super::align_up(block_size + (block_size >> SL_INDEX_COUNT_LOG2), 4096);
+        if !super::grow_heap(self.heap_end, growth) {
+            return false;
+        }
+
+        // The old sentinel becomes the header of the new block
+        let block = (self.heap_end - SENTINEL_SIZE) as *mut BlockHeader;
+        self.heap_end += growth;
+        unsafe {
+            (*block).size = growth;
+            Self::write_sentinel(self.heap_end - SENTINEL_SIZE, NonNull::new(block));
+            self.free_block(NonNull::new_unchecked(block));
+        }
+        true
+    }
+
+    /// Marks a block as free, merges it with its free neighbours and puts it on a free list.
+    ///
+    /// Unsafe because the block must be a valid block which is not on a free list.
+    unsafe fn free_block(&mut self, block: NonNull<BlockHeader>) {
+        let mut block = block;
+        unsafe {
+            if let Some(prev) = (*block.as_ptr()).prev_phys
+                && (*prev.as_ptr()).is_free()
+            {
+                self.remove_free(prev);
+                (*prev.as_ptr()).set_size((*prev.as_ptr()).size() + (*block.as_ptr()).size());
+                block = prev;
+            }
+
+            let next = (*block.as_ptr()).next_phys();
+            if (*next.as_ptr()).is_free() {
+                self.remove_free(next);
+                (*block.as_ptr()).set_size((*block.as_ptr()).size() + (*next.as_ptr()).size());
+            }
+            (*(*block.as_ptr()).next_phys().as_ptr()).prev_phys = Some(block);
+
+            (*block.as_ptr()).set_free(true);
+            self.insert_free(block);
+        }
+    }
+
+    /// Adds a free block to the front of its free list.
+    ///
+    /// Unsafe because the block must be valid and not on a free list.
+    unsafe fn insert_free(&mut self, block: NonNull<BlockHeader>) {
+        let size = unsafe { (*block.as_ptr()).size() };
+        let (fl, sl) = mapping_insert(size);
+        let head = self.free_lists[fl][sl];
+        unsafe {
+            (*block.as_ptr()).next_free = head;
+            (*block.as_ptr()).prev_free = None;
+            if let Some(head) = head {
+                (*head.as_ptr()).prev_free = Some(block);
+            }
+        }
+        self.free_lists[fl][sl] = Some(block);
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+        self.free_bytes += size - HEADER_SIZE;
+    }
+
+    /// Takes a free block off its free list.
+    ///
+    /// Unsafe because the block must be on a free list.
+    unsafe fn remove_free(&mut self, block: NonNull<BlockHeader>) {
+        let size = unsafe { (*block.as_ptr()).size() };
+        let (fl, sl) = mapping_insert(size);
+        unsafe {
+            let prev = (*block.as_ptr()).prev_free;
+            let next = (*block.as_ptr()).next_free;
+            if let Some(next) = next {
+                (*next.as_ptr()).prev_free = prev;
+            }
+            match prev {
+                Some(prev) => (*prev.as_ptr()).next_free = next,
+                None => self.free_lists[fl][sl] = next,
+            }
+        }
+        if self.free_lists[fl][sl].is_none() {
+            self.sl_bitmap[fl] &= !(1 << sl);
+            if self.sl_bitmap[fl] == 0 {
+                self.fl_bitmap &= !(1 << fl);
+            }
+        }
+        self.free_bytes -= size - HEADER_SIZE;
+    }
+
+    /// Finds a free block of at least `size` bytes with two bit scans.
+    fn find_suitable(&self, size: usize) -> Option<NonNull<BlockHeader>> {
+        let (fl, sl) = mapping_search(size);
+        if fl >= FL_INDEX_COUNT {
+            return None;
+        }
+
+        let sl_map = self.sl_bitmap[fl] & (!0 << sl);
+        let (fl, sl_map) = if sl_map != 0 {
+            (fl, sl_map)
+        } else {
+            let fl_map = self.fl_bitmap & (!0 << (fl + 1));
+            if fl_map == 0 {
+                return None;
+            }
+            let fl = fl_map.trailing_zeros() as usize;
+            (fl, self.sl_bitmap[fl])
+        };
+        self.free_lists[fl][sl_map.trailing_zeros() as usize]
+    }
+
+    /// Splits off the end of a used block, so that the block is `size` bytes large, if the
+    /// remainder can be a block of its own.
+    ///
+    /// Unsafe because the block must be valid and not on a free list.
+    unsafe fn trim(&mut self, block: NonNull<BlockHeader>, size: usize) {
+        let block_size = unsafe { (*block.as_ptr()).size() };
+        if block_size < size + MIN_BLOCK_SIZE {
+            return;
+        }
+
+        let remainder = (block.as_ptr() as usize + size) as *mut BlockHeader;
+        unsafe {
+            (*block.as_ptr()).set_size(size);
+            remainder.write(BlockHeader {
+                prev_phys: Some(block),
+                size: block_size - size,
+                next_free: None,
+                prev_free: None,
+            });
+            self.free_block(NonNull::new_unchecked(remainder));
+        }
+    }
+
+    /// Splits off the start of a used block, so that the allocation after its header is aligned to
+    /// `align`. Returns the block holding the aligned allocation.
+    ///
+    /// Unsafe because the block must be valid, not on a free list and large enough for the split.
+    unsafe fn trim_front(
+        &mut self,
+        block: NonNull<BlockHeader>,
+        align: usize,
+    ) -> NonNull<BlockHeader> {
+        let payload = block.as_ptr() as usize + HEADER_SIZE;
+        let mut aligned = super::align_up(payload, align);
+        if aligned == payload {
+            return block;
+        }
+        if aligned - payload < MIN_BLOCK_SIZE {
+            // The gap in front must be large enough to be a block of its own
+            aligned = super::align_up(payload + MIN_BLOCK_SIZE, align);
+        }
+
+        let gap = aligned - payload;
+        let block_size = unsafe { (*block.as_ptr()).size() };
+        let aligned_block = (block.as_ptr() as usize + gap) as *mut BlockHeader;
+        unsafe {
+            (*block.as_ptr()).set_size(gap);
+            aligned_block.write(BlockHeader {
+                prev_phys: Some(block),
+                size: block_size - gap,
+                next_free: None,
+                prev_free: None,
+            });
+            let aligned_block = NonNull::new_unchecked(aligned_block);
+            (*(*aligned_block.as_ptr()).next_phys().as_ptr()).prev_phys = Some(aligned_block);
+            self.free_block(block);
+            aligned_block
+        }
+    }
+
+    /// Size of the block which holds an allocation with the given layout.
+    fn block_size(layout: Layout) -> Option<usize> {
+        let payload = super::align_up(layout.size().max(MIN_BLOCK_SIZE - HEADER_SIZE), ALIGN_SIZE);
+        payload.checked_add(HEADER_SIZE)
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let Some(block_size) = Self::block_size(layout) else {
+            return ptr::null_mut();
+        };
+        // Leave room for a gap in front of the block, if it needs more alignment than every block
+        // has anyway
+        let search_size = if layout.align() > ALIGN_SIZE {
+            block_size + layout.align() + MIN_BLOCK_SIZE
+        } else {
+            block_size
+        };
+        if search_size >= 1 << FL_INDEX_MAX {
+            return ptr::null_mut();
+        }
+
+        let Some(block) = self.find_suitable(search_size).or_else(|| {
+            self.grow(search_size)
+                .then(|| self.find_suitable(search_size))?
+        }) else {
+            return ptr::null_mut();
+        };
+
+        unsafe {
+            self.remove_free(block);
+            (*block.as_ptr()).set_free(false);
+            let block = self.trim_front(block, layout.align());
+            self.trim(block, block_size);
+            self.usage
+                .record_alloc((*block.as_ptr()).size() - HEADER_SIZE);
+            (block.as_ptr() as *mut u8).add(HEADER_SIZE)
+        }
+    }
+
+    /// Unsafe because ptr must have been returned by allocate and not been freed yet.
+    unsafe fn deallocate(&mut self, ptr: *mut u8) {
+        let block = unsafe { NonNull::new_unchecked(ptr.sub(HEADER_SIZE) as *mut BlockHeader) };
+        unsafe {
+            self.usage
+                .record_dealloc((*block.as_ptr()).size() - HEADER_SIZE);
+            self.free_block(block);
+        }
+    }
+
+    /// Iterates over the blocks of the free list at the given indices.
+    fn free_list(&self, fl: usize, sl: usize) -> impl Iterator<Item = &BlockHeader> {
+        core::iter::successors(
+            self.free_lists[fl][sl].map(|block| unsafe { &*block.as_ptr() }),
+            |block| block.next_free.map(|next| unsafe { &*next.as_ptr() }),
+        )
+    }
+}
+
+impl Default for TlsfAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AllocatorStats for TlsfAllocator {
+    /// The largest free block is on the highest non-empty free list, so only that list is walked.
+    fn stats(&self) -> HeapStats {
+        let largest = if self.fl_bitmap == 0 {
+            0
+        } else {
+            let fl = self.fl_bitmap.ilog2() as usize;
+            let sl = self.sl_bitmap[fl].ilog2() as usize;
+            self.free_list(fl, sl)
+                .map(|block| block.size() - HEADER_SIZE)
+                .max()
+                .unwrap_or(0)
+        };
+        self.usage.stats(self.free_bytes, Some(largest))
+    }
+}
+
+impl Validate for TlsfAllocator {
+    /// Checks the bitmaps against the free lists, and that every free block is marked free, sits
+    /// on the list its size maps to, is linked to its neighbours and has been merged with them.
+    fn validate(&self) -> Result<(), Corruption> {
+        let mut free_bytes = 0;
+        for fl in 0..FL_INDEX_COUNT {
+            ensure(
+                (self.fl_bitmap & (1 << fl) != 0) == (self.sl_bitmap[fl] != 0),
+                fl,
+                "first level bitmap does not match the second level",
+            )?;
+            for sl in 0..SL_INDEX_COUNT {
+                ensure(
+                    (self.sl_bitmap[fl] & (1 << sl) != 0) == self.free_lists[fl][sl].is_some(),
+                    fl * SL_INDEX_COUNT + sl,
+                    "second level bitmap does not match the free list",
+                )?;
+
+                for block in self.free_list(fl, sl) {
+                    let addr = block as *const BlockHeader as usize;
+                    let block_ptr = NonNull::from(block);
+                    ensure(block.is_free(), addr, "block on a free list is not free")?;
+                    ensure(
+                        mapping_insert(block.size()) == (fl, sl),
+                        addr,
+                        "free block on the wrong free list",
+                    )?;
+                    if let Some(next_free) = block.next_free {
+                        let prev_free = unsafe { (*next_free.as_ptr()).prev_free };
+                        ensure(prev_free == Some(block_ptr), addr, "broken free list link")?;
+                    }
+
+                    let next = unsafe { &*block.next_phys().as_ptr() };
+                    ensure(
+                        next.prev_phys == Some(block_ptr),
+                        addr,
+                        "broken physical link",
+                    )?;
+                    ensure(
+                        !next.is_free(),
+                        addr,
+                        "free block not merged with the next one",
+                    )?;
+                    if let Some(prev) = block.prev_phys {
+                        let prev_free = unsafe { (*prev.as_ptr()).is_free() };
+                        ensure(
+                            !prev_free,
+                            addr,
+                            "free block not merged with the previous one",
+                        )?;
+                    }
+                    free_bytes += block.size() - HEADER_SIZE;
+                }
+            }
+        }
+        ensure(
+            free_bytes == self.free_bytes,
+            self.heap_end,
+            "free_bytes does not match the free lists",
+        )
+    }
+}
+
+unsafe impl GlobalAlloc for super::Locked<TlsfAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        unsafe { self.lock().deallocate(ptr) };
+    }
+}
+
+#[cfg(test)]
+mod mapping {
+    use super::*;
+
+    #[test_case]
+    fn small_sizes_map_linearly() {
+        assert_eq!(mapping_insert(32), (0, 2));
+        assert_eq!(mapping_insert(48), (0, 3));
+        assert_eq!(
+            mapping_insert(SMALL_BLOCK_SIZE - 16),
+            (0, SL_INDEX_COUNT - 1)
+        );
+    }
+
+    #[test_case]
+    fn large_sizes_map_logarithmically() {
+        assert_eq!(mapping_insert(SMALL_BLOCK_SIZE), (1, 0));
+        assert_eq!(
+            mapping_insert(2 * SMALL_BLOCK_SIZE - 16),
+            (1, SL_INDEX_COUNT - 1)
+        );
+        assert_eq!(mapping_insert(4096), (5, 0));
+        assert_eq!(mapping_insert(4096 + 256), (5, 1));
+        assert_eq!(mapping_insert(4096 + 255), (5, 0));
+    }
+
+    #[test_case]
+    fn heaps_of_any_size_map_to_a_list() {
+        assert_eq!(mapping_insert(1 << 32).0, 32 - FL_INDEX_SHIFT + 1);
+        assert_eq!(
+            mapping_insert((1 << FL_INDEX_MAX) - 1),
+            (FL_INDEX_COUNT - 1, SL_INDEX_COUNT - 1)
+        );
+    }
+
+    #[test_case]
+    fn search_rounds_up_to_next_list() {
+        assert_eq!(mapping_search(4096), (5, 0));
+        assert_eq!(mapping_search(4097), (5, 1));
+        assert_eq!(mapping_search(48), (0, 3));
+    }
+}
+
+#[cfg(test)]
+mod alloc_dealloc {
+    use super::*;
+
+    const TEST_HEAP_SIZE: usize = 8192;
+
+    #[repr(align(16))]
+    struct TestHeap([u8; TEST_HEAP_SIZE]);
+
+    /// Creates an allocator on `test_heap`, which cannot grow.
+    fn new_allocator(test_heap: &mut TestHeap) -> TlsfAllocator {
+        let mut allocator = TlsfAllocator::new();
+        unsafe { allocator.init(test_heap.0.as_mut_ptr() as usize, TEST_HEAP_SIZE) };
+        allocator
+    }
+
+    /// Free bytes of a heap of TEST_HEAP_SIZE with a single free block.
+    const INITIAL_FREE: usize = TEST_HEAP_SIZE - HEADER_SIZE - SENTINEL_SIZE;
+
+    #[test_case]
+    fn init_creates_single_free_block() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let allocator = new_allocator(&mut test_heap);
+        let stats = allocator.stats();
+        assert_eq!(stats.free_bytes, INITIAL_FREE);
+        assert_eq!(stats.largest_free_block, Some(INITIAL_FREE));
+    }
+
+    #[test_case]
+    fn allocations_are_adjacent() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let mut allocator = new_allocator(&mut test_heap);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        let ptr_1 = allocator.allocate(layout);
+        let ptr_2 = allocator.allocate(layout);
+        assert_eq!(ptr_1 as usize, test_heap.0.as_ptr() as usize + HEADER_SIZE);
+        assert_eq!(ptr_2 as usize, ptr_1 as usize + 64 + HEADER_SIZE);
+    }
+
+    #[test_case]
+    fn small_allocations_fit_free_list_links() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let mut allocator = new_allocator(&mut test_heap);
+
+        let ptr_1 = allocator.allocate(Layout::new::<u8>());
+        let ptr_2 = allocator.allocate(Layout::new::<u8>());
+        assert_eq!(ptr_2 as usize - ptr_1 as usize, MIN_BLOCK_SIZE);
+        assert_eq!(
+            allocator.stats().allocated_bytes,
+            2 * (MIN_BLOCK_SIZE - HEADER_SIZE)
+        );
+    }
+
+    #[test_case]
+    fn freed_blocks_are_merged() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let mut allocator = new_allocator(&mut test_heap);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        let ptrs = [(); 4].map(|_| allocator.allocate(layout));
+        // free out of order, so that blocks are merged with both neighbours
+        for idx in [1, 3, 0, 2] {
+            unsafe { allocator.deallocate(ptrs[idx]) };
+        }
+
+        let stats = allocator.stats();
+        assert_eq!(stats.free_bytes, INITIAL_FREE);
+        assert_eq!(stats.largest_free_block, Some(INITIAL_FREE));
+        assert_eq!(stats.live_allocations, 0);
+        assert_eq!(allocator.fl_bitmap.count_ones(), 1);
+    }
+
+    #[test_case]
+    fn freed_block_is_reused() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let mut allocator = new_allocator(&mut test_heap);
+        let layout = Layout::from_size_align(256, 8).unwrap();
+
+        let ptr_1 = allocator.allocate(layout);
+        let _ptr_2 = allocator.allocate(layout);
+        unsafe { allocator.deallocate(ptr_1) };
+        assert_eq!(allocator.allocate(layout), ptr_1);
+    }
+
+    #[test_case]
+    fn exhausted_heap_returns_null() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let mut allocator = new_allocator(&mut test_heap);
+
+        let too_large = Layout::from_size_align(TEST_HEAP_SIZE, 8).unwrap();
+        assert!(allocator.allocate(too_large).is_null());
+
+        // the second allocation does not fit into what is left after the first
+        let half = Layout::from_size_align(TEST_HEAP_SIZE / 2, 8).unwrap();
+        let ptr = allocator.allocate(half);
+        assert!(!ptr.is_null());
+        assert!(allocator.allocate(half).is_null());
+
+        unsafe { allocator.deallocate(ptr) };
+        assert!(!allocator.allocate(half).is_null());
+    }
+
+    #[test_case]
+    fn large_alignment() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let mut allocator = new_allocator(&mut test_heap);
+
+        for align in [32, 64, 256, 1024] {
+            let layout = Layout::from_size_align(40, align).unwrap();
+            let ptr = allocator.allocate(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0, "allocation should be aligned");
+        }
+    }
+
+    #[test_case]
+    fn alignment_gap_is_freed() {
+        let mut test_heap = TestHeap([0; TEST_HEAP_SIZE]);
+        let mut allocator = new_allocator(&mut test_heap);
+
+        let layout = Layout::from_size_align(64, 1024).unwrap();
+        let ptr = allocator.allocate(layout);
+        assert_eq!(allocator.stats().allocated_bytes, 64);
+
+        unsafe { allocator.deallocate(ptr) };
+        let stats = allocator.stats();
+        assert_eq!(stats.free_bytes, INITIAL_FREE);
+        assert_eq!(stats.largest_free_block, Some(INITIAL_FREE));
+    }
+}
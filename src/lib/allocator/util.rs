@@ -0,0 +1,61 @@
+// Nothing in here may depend on the kernel, so that the allocators can be tested on the host too
+// (see host-tests/)
+
+use spin::MutexGuard;
+
+/// A generic wrapper around spin::Mutex to get around the orphan rules
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+/// Align the given address upwards to alignment
+///
+/// Requires that `alignment` is a power of two, which is guaranteed by
+/// core::alloc::Layout::align()
+pub(super) fn align_up(addr: usize, alignment: usize) -> usize {
+    // e.g.
+    // alignment = 8    = 0...01000
+    // alignment - 1    = 0...00111
+    // !(alignment - 1) = 1...11000
+    // addr & !(alignment - 1) will remove the last 3 bits, effectively aligning downwards
+    // since we want to align upwards, we add (alignment - 1) first
+    let ones_below = alignment - 1;
+    let align_down_mask = !(ones_below);
+    (addr + ones_below) & align_down_mask
+}
+
+#[cfg(test)]
+mod align_up {
+    use super::*;
+
+    #[test_case]
+    fn aligned_addr_is_unchanged() {
+        assert_eq!(align_up(0, 8), 0);
+        assert_eq!(align_up(64, 16), 64);
+        assert_eq!(align_up(4096, 4096), 4096);
+    }
+
+    #[test_case]
+    fn rounds_up_to_next_multiple() {
+        assert_eq!(align_up(1, 8), 8);
+        assert_eq!(align_up(17, 16), 32);
+        assert_eq!(align_up(4097, 4096), 8192);
+    }
+
+    #[test_case]
+    fn alignment_of_one_is_noop() {
+        assert_eq!(align_up(12345, 1), 12345);
+    }
+}
@@ -0,0 +1,30 @@
+use core::fmt;
+
+/// An inconsistency in the bookkeeping of a heap allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corruption {
+    /// Address of the free block or region which is inconsistent
+    pub addr: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {:#x}", self.reason, self.addr)
+    }
+}
+
+/// Implemented by every heap allocator, so that tests can check its free lists after every step.
+pub trait Validate {
+    /// Walks the allocator's free lists and checks them against the rest of its bookkeeping.
+    fn validate(&self) -> Result<(), Corruption>;
+}
+
+/// Returns a Corruption if `condition` does not hold.
+pub(super) fn ensure(condition: bool, addr: usize, reason: &'static str) -> Result<(), Corruption> {
+    if condition {
+        Ok(())
+    } else {
+        Err(Corruption { addr, reason })
+    }
+}
@@ -64,8 +64,14 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    use crate::memory::demand_paging;
     use x86_64::registers::control::Cr2;
 
+    // Faults on reserved ranges are expected, they get backed by a frame and the access is retried
+    if demand_paging::handle_page_fault(Cr2::read(), error_code) {
+        return;
+    }
+
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
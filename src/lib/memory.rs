@@ -1,9 +1,20 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use buddy::BuddyFrameAllocator;
+use spin::Mutex;
 use x86_64::{
-    PhysAddr, VirtAddr,
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    VirtAddr,
+    structures::paging::{OffsetPageTable, PageTable},
 };
 
+pub mod bitmap;
+pub mod buddy;
+pub mod demand_paging;
+pub mod dma;
+pub mod free_list;
+pub mod mmio;
+pub mod page_tables;
+pub mod vmalloc;
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// This function is unsafe because the caller must guarantee that the complete physical memory is
@@ -21,42 +32,114 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     unsafe { &mut *page_table_ptr }
 }
 
+/// Sets up the PAT for `mmio::CacheMode` and returns a mapper for the active page tables.
+///
+/// # Safety
+///
+/// The complete physical memory must be mapped at `physical_memory_offset`, and this function must
+/// only be called once, on the boot CPU, before anything else is mapped.
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     unsafe {
+        mmio::init_pat();
         let level_4_table = active_level_4_table(physical_memory_offset);
         OffsetPageTable::new(level_4_table, physical_memory_offset)
     }
 }
 
-/// Uses the memory map provided in the BootInfo from the bootloader to allocate free physical
-/// memory regions
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+/// The page table mapper and frame allocator the kernel uses after boot.
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BuddyFrameAllocator,
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map: memory_map,
-            next: 0,
-        }
+/// Set by `init_kernel_memory`, used by code which needs to map memory on the fly, e.g. to grow the
+/// heap.
+///
+/// Heap growth and the demand paging fault handler lock this, so never allocate on the heap or touch
+/// any other memory in a `demand_paging` reserved range while holding the lock. A page fault which
+/// finds the lock held panics, instead of spinning on it forever.
+pub static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+/// Hands the mapper and frame allocator over to the kernel, so they can be used after boot.
+pub fn init_kernel_memory(mapper: OffsetPageTable<'static>, frame_allocator: BuddyFrameAllocator) {
+    *KERNEL_MEMORY.lock() = Some(KernelMemory {
+        mapper,
+        frame_allocator,
+    });
+}
+
+/// Returns the total size of the usable regions in the memory map, in bytes.
+pub fn usable_memory(memory_map: &MemoryMap) -> u64 {
+    memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable)
+        .map(|region| region.range.end_addr() - region.range.start_addr())
+        .sum()
+}
+
+#[cfg(test)]
+mod usable_memory {
+    use super::*;
+    use bootloader::bootinfo::{FrameRange, MemoryRegion};
+
+    #[test_case]
+    fn counts_usable_regions() {
+        assert_eq!(
+            usable_memory(&test_utils::TEST_MEMORY_MAP),
+            (test_utils::TEST_FRAMES * 4096) as u64
+        );
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        self.memory_map
-            .iter()
-            .filter(|r| r.region_type == MemoryRegionType::Usable)
-            .map(|r| r.range.start_addr()..r.range.end_addr())
-            .flat_map(|r| r.step_by(4096))
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    #[test_case]
+    fn ignores_other_regions() {
+        let mut memory_map = MemoryMap::new();
+        for (start, region_type) in [
+            (0x0, MemoryRegionType::Usable),
+            (0x2000, MemoryRegionType::Kernel),
+            (0x4000, MemoryRegionType::Usable),
+            (0x6000, MemoryRegionType::Reserved),
+        ] {
+            memory_map.add_region(MemoryRegion {
+                range: FrameRange::new(start, start + 0x2000),
+                region_type,
+            });
+        }
+        assert_eq!(usable_memory(&memory_map), 0x4000);
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+#[cfg(test)]
+mod test_utils {
+    use bootloader::bootinfo::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
+    use lazy_static::lazy_static;
+
+    /// Number of 4KiB frames backing the fake physical memory used in tests.
+    pub const TEST_FRAMES: usize = 16;
+
+    #[allow(dead_code)]
+    #[repr(align(4096))]
+    struct FrameBuffer([u8; TEST_FRAMES * 4096]);
+
+    static mut TEST_MEMORY: FrameBuffer = FrameBuffer([0; TEST_FRAMES * 4096]);
+
+    lazy_static! {
+        /// A MemoryMap with a single usable region covering `TEST_MEMORY`.
+        ///
+        /// Frame allocators built from this map must use a physical memory offset of 0, so that
+        /// the "physical" addresses they hand out are the virtual addresses of `TEST_MEMORY`.
+        pub static ref TEST_MEMORY_MAP: MemoryMap = {
+            let start = (&raw const TEST_MEMORY) as u64;
+            let mut memory_map = MemoryMap::new();
+            memory_map.add_region(MemoryRegion {
+                range: FrameRange::new(start, start + (TEST_FRAMES * 4096) as u64),
+                region_type: MemoryRegionType::Usable,
+            });
+            memory_map
+        };
+    }
+
+    /// Start address of the single usable region in `TEST_MEMORY_MAP`.
+    pub fn test_memory_start() -> u64 {
+        TEST_MEMORY_MAP[0].range.start_addr()
     }
 }
@@ -0,0 +1,348 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::slice;
+use x86_64::{
+    PhysAddr, VirtAddr, align_up,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB, frame::PhysFrameRange,
+    },
+};
+
+/// Upper bound for devices that can only address 32 bits of physical memory.
+pub const BELOW_4GIB: PhysAddr = PhysAddr::new(1 << 32);
+/// Upper bound for legacy ISA DMA, which can only address 24 bits of physical memory.
+pub const BELOW_16MIB: PhysAddr = PhysAddr::new(1 << 24);
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// A physical frame allocator which keeps one bit per frame.
+///
+/// The bitmap covers every frame between the lowest and highest usable address in the memory map.
+/// A set bit means the frame is in use or not usable at all. The bitmap itself is stored in the
+/// first usable region large enough to hold it, and accessed through the physical memory mapping.
+///
+/// Allocating a single frame is a linear scan (starting from where the last allocation ended), but
+/// unlike FreeListFrameAllocator, this allocator can hand out physically contiguous ranges with a
+/// given alignment and upper address bound.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// Frame number of the frame represented by bit 0
+    first_frame: u64,
+    /// Number of frames covered by the bitmap
+    frame_count: usize,
+    usable_frames: usize,
+    free_frames: usize,
+    /// Index at which the next search for a single free frame starts
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Creates a frame allocator from the memory map passed by the bootloader.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the memory map is valid, i.e. all frames marked as `Usable`
+    /// are really unused, and that the complete physical memory is mapped at
+    /// `physical_memory_offset`. This method must only be called once.
+    pub unsafe fn init(memory_map: &MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable && !r.range.is_empty())
+                .map(|r| r.range.start_frame_number..r.range.end_frame_number)
+        };
+
+        let Some(first_frame) = usable_regions().map(|r| r.start).min() else {
+            return BitmapFrameAllocator {
+                bitmap: &mut [],
+                first_frame: 0,
+                frame_count: 0,
+                usable_frames: 0,
+                free_frames: 0,
+                next: 0,
+            };
+        };
+        let end_frame = usable_regions().map(|r| r.end).max().unwrap();
+        let frame_count = (end_frame - first_frame) as usize;
+
+        // Find a home for the bitmap
+        let words = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_frames = (words * 8).div_ceil(FRAME_SIZE as usize) as u64;
+        let bitmap_region = usable_regions()
+            .find(|r| r.end - r.start >= bitmap_frames)
+            .expect("no usable region is large enough to hold the frame bitmap");
+        let bitmap_addr = physical_memory_offset + bitmap_region.start * FRAME_SIZE;
+        let bitmap = unsafe { slice::from_raw_parts_mut(bitmap_addr.as_mut_ptr::<u64>(), words) };
+
+        // Everything starts out unusable, then usable regions are freed
+        bitmap.fill(!0);
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            first_frame,
+            frame_count,
+            usable_frames: 0,
+            free_frames: 0,
+            next: 0,
+        };
+        for region in usable_regions() {
+            for frame_number in region {
+                allocator.set_free(allocator.index_of(frame_number));
+                allocator.usable_frames += 1;
+            }
+        }
+        allocator.free_frames = allocator.usable_frames;
+
+        // The frames holding the bitmap are in use for as long as the allocator lives
+        let bitmap_start = allocator.index_of(bitmap_region.start);
+        for idx in bitmap_start..bitmap_start + bitmap_frames as usize {
+            allocator.set_used(idx);
+        }
+        allocator.free_frames -= bitmap_frames as usize;
+
+        allocator
+    }
+
+    /// Number of usable frames in the memory map, including the ones holding the bitmap.
+    pub fn total_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Number of frames which are currently available for allocation.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of usable frames which are currently allocated.
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    /// Allocates `count` physically contiguous frames.
+    ///
+    /// The first frame is aligned to `align` bytes, which must be a power of two (alignments below
+    /// the frame size are rounded up to it). If `limit` is given, the whole range lies below that
+    /// physical address, e.g. `BELOW_4GIB` for devices that only handle 32-bit addresses.
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align: u64,
+        limit: Option<PhysAddr>,
+    ) -> Option<PhysFrameRange> {
+        assert!(align.is_power_of_two(), "align must be a power of two");
+        if count == 0 {
+            return None;
+        }
+
+        let align_frames = (align / FRAME_SIZE).max(1);
+        let end = match limit {
+            Some(limit) => (limit.as_u64() / FRAME_SIZE)
+                .saturating_sub(self.first_frame)
+                .min(self.frame_count as u64) as usize,
+            None => self.frame_count,
+        };
+        let next_candidate = |idx: usize| {
+            (align_up(self.first_frame + idx as u64, align_frames) - self.first_frame) as usize
+        };
+
+        let mut start = next_candidate(0);
+        while start + count <= end {
+            match (start..start + count).rev().find(|&idx| self.is_used(idx)) {
+                // skip past the used frame, nothing before it can hold the range
+                Some(used) => start = next_candidate(used + 1),
+                None => {
+                    for idx in start..start + count {
+                        self.set_used(idx);
+                    }
+                    self.free_frames -= count;
+                    return Some(PhysFrame::range(
+                        self.frame_at(start),
+                        self.frame_at(start + count),
+                    ));
+                }
+            }
+        }
+        None
+    }
+
+    /// Frees every frame in `range`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the frames are unused and were allocated from this
+    /// allocator.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            unsafe { self.deallocate_frame(frame) };
+        }
+    }
+
+    fn index_of(&self, frame_number: u64) -> usize {
+        (frame_number - self.first_frame) as usize
+    }
+
+    fn frame_at(&self, idx: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new((self.first_frame + idx as u64) * FRAME_SIZE))
+    }
+
+    fn is_used(&self, idx: usize) -> bool {
+        self.bitmap[idx / BITS_PER_WORD] & (1 << (idx % BITS_PER_WORD)) != 0
+    }
+
+    fn set_used(&mut self, idx: usize) {
+        self.bitmap[idx / BITS_PER_WORD] |= 1 << (idx % BITS_PER_WORD);
+    }
+
+    fn set_free(&mut self, idx: usize) {
+        self.bitmap[idx / BITS_PER_WORD] &= !(1 << (idx % BITS_PER_WORD));
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let words = self.bitmap.len();
+        let first_word = self.next / BITS_PER_WORD;
+        for i in 0..words {
+            let word_idx = (first_word + i) % words;
+            let word = self.bitmap[word_idx];
+            if word != !0 {
+                // bits past frame_count are always set, so this is a valid frame
+                let idx = word_idx * BITS_PER_WORD + word.trailing_ones() as usize;
+                self.set_used(idx);
+                self.free_frames -= 1;
+                self.next = idx + 1;
+                return Some(self.frame_at(idx));
+            }
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let frame_number = frame.start_address().as_u64() / FRAME_SIZE;
+        assert!(
+            frame_number >= self.first_frame && self.index_of(frame_number) < self.frame_count,
+            "frame {frame:?} is not tracked by this allocator"
+        );
+        let idx = self.index_of(frame_number);
+        assert!(self.is_used(idx), "frame {frame:?} is already free");
+
+        self.set_free(idx);
+        self.free_frames += 1;
+    }
+}
+
+#[cfg(test)]
+mod bitmap_frame_allocator {
+    use super::*;
+    use crate::memory::test_utils::{TEST_FRAMES, TEST_MEMORY_MAP, test_memory_start};
+
+    fn new_test_allocator() -> BitmapFrameAllocator {
+        unsafe { BitmapFrameAllocator::init(&TEST_MEMORY_MAP, VirtAddr::new(0)) }
+    }
+
+    /// Address of the nth frame of the test memory.
+    fn frame_addr(n: u64) -> u64 {
+        test_memory_start() + n * FRAME_SIZE
+    }
+
+    #[test_case]
+    fn bitmap_occupies_first_frame() {
+        let mut allocator = new_test_allocator();
+        assert_eq!(allocator.total_frames(), TEST_FRAMES);
+        assert_eq!(allocator.used_frames(), 1);
+        assert_eq!(allocator.free_frames(), TEST_FRAMES - 1);
+
+        let frame = allocator.allocate_frame().unwrap();
+        assert_eq!(frame.start_address().as_u64(), frame_addr(1));
+    }
+
+    #[test_case]
+    fn counts_follow_allocations() {
+        let mut allocator = new_test_allocator();
+        let frame = allocator.allocate_frame().unwrap();
+        assert_eq!(allocator.used_frames(), 2);
+
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.used_frames(), 1);
+        assert_eq!(allocator.free_frames(), TEST_FRAMES - 1);
+    }
+
+    #[test_case]
+    fn deallocated_frame_is_reused() {
+        let mut allocator = new_test_allocator();
+        let mut frames = [None; TEST_FRAMES - 1];
+        for frame in frames.iter_mut() {
+            *frame = allocator.allocate_frame();
+        }
+        assert!(allocator.allocate_frame().is_none());
+
+        let freed = frames[4].unwrap();
+        unsafe { allocator.deallocate_frame(freed) };
+        assert_eq!(allocator.allocate_frame(), Some(freed));
+    }
+
+    #[test_case]
+    fn contiguous_allocation() {
+        let mut allocator = new_test_allocator();
+        let range = allocator
+            .allocate_contiguous(4, FRAME_SIZE, None)
+            .expect("4 contiguous frames should be available");
+        assert_eq!(range.start.start_address().as_u64(), frame_addr(1));
+        assert_eq!(range.end.start_address().as_u64(), frame_addr(5));
+        assert_eq!(allocator.used_frames(), 5);
+    }
+
+    #[test_case]
+    fn contiguous_allocation_skips_used_frames() {
+        let mut allocator = new_test_allocator();
+        let first = allocator.allocate_contiguous(2, FRAME_SIZE, None).unwrap();
+        let second = allocator.allocate_contiguous(2, FRAME_SIZE, None).unwrap();
+
+        // free a single frame in the middle, which is too small for 2 frames
+        unsafe { allocator.deallocate_frame(first.start) };
+        let third = allocator.allocate_contiguous(2, FRAME_SIZE, None).unwrap();
+        assert_eq!(third.start, second.end);
+    }
+
+    #[test_case]
+    fn contiguous_allocation_is_aligned() {
+        let mut allocator = new_test_allocator();
+        let align = 4 * FRAME_SIZE;
+        let range = allocator.allocate_contiguous(2, align, None).unwrap();
+        assert_eq!(range.start.start_address().as_u64() % align, 0);
+        assert!(range.start.start_address().as_u64() >= frame_addr(1));
+    }
+
+    #[test_case]
+    fn contiguous_allocation_respects_limit() {
+        let mut allocator = new_test_allocator();
+        let limit = PhysAddr::new(frame_addr(5));
+
+        let range = allocator.allocate_contiguous(4, FRAME_SIZE, Some(limit));
+        assert!(range.is_some(), "frames 1 to 4 are below the limit");
+        assert!(
+            allocator
+                .allocate_contiguous(1, FRAME_SIZE, Some(limit))
+                .is_none(),
+            "no frames left below the limit"
+        );
+        assert!(allocator.allocate_contiguous(1, FRAME_SIZE, None).is_some());
+    }
+
+    #[test_case]
+    fn deallocated_range_can_be_allocated_again() {
+        let mut allocator = new_test_allocator();
+        let range = allocator
+            .allocate_contiguous(TEST_FRAMES - 1, FRAME_SIZE, None)
+            .unwrap();
+        assert_eq!(allocator.free_frames(), 0);
+
+        unsafe { allocator.deallocate_contiguous(range) };
+        assert_eq!(allocator.free_frames(), TEST_FRAMES - 1);
+        assert_eq!(
+            allocator.allocate_contiguous(TEST_FRAMES - 1, FRAME_SIZE, None),
+            Some(range)
+        );
+    }
+}
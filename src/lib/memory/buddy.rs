@@ -0,0 +1,278 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame},
+};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Largest block order. A block of order `n` spans 2^n frames, so order 18 is 1GiB.
+pub const MAX_ORDER: usize = 18;
+
+/// Written into the first bytes of every free block.
+struct FreeBlock {
+    next: Option<PhysAddr>,
+}
+
+/// A binary buddy allocator for physical frames.
+///
+/// Free memory is kept as power-of-two sized blocks, one free list per order. Allocating a block
+/// splits a larger one if needed, and freeing a block merges it with its buddy (the other half of
+/// the block it was split from) for as long as that buddy is free too. The free lists are stored
+/// inside the free blocks themselves, through the physical memory mapping.
+///
+/// Finding the buddy walks the free list of its order, so freeing is linear in the number of free
+/// blocks of that order.
+pub struct BuddyFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    free_lists: [Option<PhysAddr>; MAX_ORDER + 1],
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// Creates a buddy allocator from the memory map passed by the bootloader.
+    ///
+    /// Every usable region is cut into the largest blocks which are aligned to their own size.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the memory map is valid, i.e. all frames marked as `Usable`
+    /// are really unused, and that the complete physical memory is mapped at
+    /// `physical_memory_offset`. This method must only be called once.
+    pub unsafe fn init(memory_map: &MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let mut allocator = BuddyFrameAllocator {
+            physical_memory_offset,
+            free_lists: [None; MAX_ORDER + 1],
+            free_frames: 0,
+        };
+
+        let usable_regions = memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| r.range.start_addr()..r.range.end_addr());
+        for region in usable_regions {
+            let mut addr = region.start;
+            while addr < region.end {
+                let order = (0..=MAX_ORDER)
+                    .rev()
+                    .find(|&order| {
+                        addr % block_size(order) == 0 && addr + block_size(order) <= region.end
+                    })
+                    .unwrap();
+                unsafe { allocator.push(PhysAddr::new(addr), order) };
+                allocator.free_frames += 1 << order;
+                addr += block_size(order);
+            }
+        }
+
+        allocator
+    }
+
+    /// Number of frames which are currently available for allocation.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Allocates a block of 2^`order` contiguous frames, aligned to its size.
+    pub fn allocate(&mut self, order: usize) -> Option<PhysAddr> {
+        assert!(order <= MAX_ORDER, "order must be at most {MAX_ORDER}");
+
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o].is_some())?;
+        let addr = self.pop(current).unwrap();
+
+        // Split the block until it has the requested order, returning the upper halves
+        while current > order {
+            current -= 1;
+            unsafe { self.push(addr + block_size(current), current) };
+        }
+
+        self.free_frames -= 1 << order;
+        Some(addr)
+    }
+
+    /// Frees a block of 2^`order` frames, merging it with its buddies where possible.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the block is unused and was returned by `allocate` with the
+    /// same order.
+    pub unsafe fn deallocate(&mut self, addr: PhysAddr, order: usize) {
+        assert!(order <= MAX_ORDER, "order must be at most {MAX_ORDER}");
+        self.free_frames += 1 << order;
+
+        let mut addr = addr;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = PhysAddr::new(addr.as_u64() ^ block_size(order));
+            if !self.remove(buddy, order) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        unsafe { self.push(addr, order) };
+    }
+
+    fn free_block_ptr(&self, addr: PhysAddr) -> *mut FreeBlock {
+        (self.physical_memory_offset + addr.as_u64()).as_mut_ptr()
+    }
+
+    /// Adds a block to the front of the free list for `order`.
+    ///
+    /// Unsafe because the caller must guarantee the block is unused.
+    unsafe fn push(&mut self, addr: PhysAddr, order: usize) {
+        let block = FreeBlock {
+            next: self.free_lists[order].take(),
+        };
+        unsafe { self.free_block_ptr(addr).write(block) };
+        self.free_lists[order] = Some(addr);
+    }
+
+    /// Removes the first block from the free list for `order`.
+    fn pop(&mut self, order: usize) -> Option<PhysAddr> {
+        let addr = self.free_lists[order]?;
+        self.free_lists[order] = unsafe { self.free_block_ptr(addr).read().next };
+        Some(addr)
+    }
+
+    /// Removes the block at `addr` from the free list for `order`.
+    ///
+    /// Returns false if the block is not on that list.
+    fn remove(&mut self, addr: PhysAddr, order: usize) -> bool {
+        if self.free_lists[order] == Some(addr) {
+            self.pop(order);
+            return true;
+        }
+
+        let mut curr = self.free_lists[order];
+        while let Some(curr_addr) = curr {
+            let curr_ptr = self.free_block_ptr(curr_addr);
+            let next = unsafe { (*curr_ptr).next };
+            if next == Some(addr) {
+                unsafe { (*curr_ptr).next = self.free_block_ptr(addr).read().next };
+                return true;
+            }
+            curr = next;
+        }
+        false
+    }
+}
+
+/// Size in bytes of a block of the given order.
+fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+/// Order of the blocks backing a page of size `S`.
+fn order_of<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE).trailing_zeros() as usize
+}
+
+unsafe impl<S: PageSize> FrameAllocator<S> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        self.allocate(order_of::<S>())
+            .map(PhysFrame::containing_address)
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        unsafe { self.deallocate(frame.start_address(), order_of::<S>()) };
+    }
+}
+
+#[cfg(test)]
+mod buddy_frame_allocator {
+    use super::*;
+    use crate::memory::test_utils::{TEST_FRAMES, TEST_MEMORY_MAP};
+    use x86_64::structures::paging::{Size2MiB, Size4KiB};
+
+    fn new_test_allocator() -> BuddyFrameAllocator {
+        unsafe { BuddyFrameAllocator::init(&TEST_MEMORY_MAP, VirtAddr::new(0)) }
+    }
+
+    fn free_list_lengths(allocator: &BuddyFrameAllocator) -> [usize; MAX_ORDER + 1] {
+        let mut lengths = [0; MAX_ORDER + 1];
+        for (order, length) in lengths.iter_mut().enumerate() {
+            let mut curr = allocator.free_lists[order];
+            while let Some(addr) = curr {
+                *length += 1;
+                curr = unsafe { allocator.free_block_ptr(addr).read().next };
+            }
+        }
+        lengths
+    }
+
+    #[test_case]
+    fn order_of_page_sizes() {
+        assert_eq!(order_of::<Size4KiB>(), 0);
+        assert_eq!(order_of::<Size2MiB>(), 9);
+        assert_eq!(
+            order_of::<x86_64::structures::paging::Size1GiB>(),
+            MAX_ORDER
+        );
+    }
+
+    #[test_case]
+    fn blocks_are_aligned_to_their_size() {
+        let allocator = new_test_allocator();
+        assert_eq!(allocator.free_frames(), TEST_FRAMES);
+        for order in 0..=MAX_ORDER {
+            let mut curr = allocator.free_lists[order];
+            while let Some(addr) = curr {
+                assert_eq!(addr.as_u64() % block_size(order), 0);
+                curr = unsafe { allocator.free_block_ptr(addr).read().next };
+            }
+        }
+    }
+
+    #[test_case]
+    fn allocates_every_frame() {
+        let mut allocator = new_test_allocator();
+        for _ in 0..TEST_FRAMES {
+            let frame: Option<PhysFrame<Size4KiB>> = allocator.allocate_frame();
+            assert!(frame.is_some());
+        }
+        assert_eq!(allocator.free_frames(), 0);
+        assert!(FrameAllocator::<Size4KiB>::allocate_frame(&mut allocator).is_none());
+    }
+
+    #[test_case]
+    fn frees_coalesce_back_into_original_blocks() {
+        let mut allocator = new_test_allocator();
+        let initial = free_list_lengths(&allocator);
+
+        let mut frames = [None; TEST_FRAMES];
+        for frame in frames.iter_mut() {
+            *frame = FrameAllocator::<Size4KiB>::allocate_frame(&mut allocator);
+        }
+        for frame in frames {
+            unsafe { allocator.deallocate_frame(frame.unwrap()) };
+        }
+
+        assert_eq!(allocator.free_frames(), TEST_FRAMES);
+        assert_eq!(free_list_lengths(&allocator), initial);
+    }
+
+    #[test_case]
+    fn split_block_is_returned_whole() {
+        let mut allocator = new_test_allocator();
+        let initial = free_list_lengths(&allocator);
+        let largest = (0..=MAX_ORDER).rev().find(|&o| initial[o] > 0).unwrap();
+
+        let addr = allocator.allocate(0).unwrap();
+        assert_eq!(allocator.free_frames(), TEST_FRAMES - 1);
+
+        unsafe { allocator.deallocate(addr, 0) };
+        assert_eq!(free_list_lengths(&allocator), initial);
+        assert!(allocator.allocate(largest).is_some());
+    }
+
+    #[test_case]
+    fn too_large_order_fails() {
+        let mut allocator = new_test_allocator();
+        let frame: Option<PhysFrame<Size2MiB>> = allocator.allocate_frame();
+        assert!(frame.is_none(), "test memory is smaller than 2MiB");
+        assert_eq!(allocator.free_frames(), TEST_FRAMES);
+    }
+}
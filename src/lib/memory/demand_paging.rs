@@ -0,0 +1,141 @@
+use super::{KERNEL_MEMORY, KernelMemory, buddy::BuddyFrameAllocator};
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize,
+            PageTableFlags, Size2MiB, Size4KiB,
+        },
+    },
+};
+
+/// Maximum number of virtual ranges which can be reserved at the same time.
+const MAX_RESERVED_RANGES: usize = 8;
+
+/// Virtual ranges which are backed by frames lazily, when they are first accessed.
+static RESERVED_RANGES: Mutex<[Option<Range<VirtAddr>>; MAX_RESERVED_RANGES]> =
+    Mutex::new([const { None }; MAX_RESERVED_RANGES]);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReserveError {
+    /// The range overlaps a range which is already reserved
+    Overlap,
+    /// All MAX_RESERVED_RANGES slots are in use
+    TooManyRanges,
+}
+
+/// Reserves the virtual range `start..start + size` without mapping it.
+///
+/// Every page in the range gets mapped to a zeroed frame by the page fault handler, the first time
+/// it is accessed. The range is extended outwards to page boundaries.
+pub fn reserve(start: VirtAddr, size: u64) -> Result<(), ReserveError> {
+    let range = start.align_down(4096u64)..(start + size).align_up(4096u64);
+
+    let mut reserved = RESERVED_RANGES.lock();
+    let overlaps = reserved
+        .iter()
+        .flatten()
+        .any(|r| r.start < range.end && range.start < r.end);
+    if overlaps {
+        return Err(ReserveError::Overlap);
+    }
+
+    let slot = reserved
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(ReserveError::TooManyRanges)?;
+    *slot = Some(range);
+    Ok(())
+}
+
+/// Returns whether `addr` lies within a reserved range.
+pub fn is_reserved(addr: VirtAddr) -> bool {
+    reserved_range(addr).is_some()
+}
+
+/// Returns the reserved range containing `addr`, if any.
+fn reserved_range(addr: VirtAddr) -> Option<Range<VirtAddr>> {
+    RESERVED_RANGES
+        .lock()
+        .iter()
+        .flatten()
+        .find(|r| r.contains(&addr))
+        .cloned()
+}
+
+/// Called by the page fault handler to back a reserved page on its first access.
+///
+/// The page is backed by a 2MiB frame if the whole 2MiB page around `addr` is reserved and nothing
+/// in it is mapped yet, and by a 4KiB frame otherwise.
+///
+/// Panics if KERNEL_MEMORY is already locked, see its documentation.
+///
+/// Returns true if the fault was resolved, in which case the faulting instruction can simply be
+/// retried. Faults on present pages (e.g. writes to read-only pages) and faults outside of reserved
+/// ranges are never handled here.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let Some(range) = reserved_range(addr) else {
+        return false;
+    };
+
+    // The faulting code may hold the lock itself, in which case waiting for it would never return
+    let Some(mut kernel_memory) = KERNEL_MEMORY.try_lock() else {
+        panic!("page fault on unbacked page {addr:?} while KERNEL_MEMORY is locked");
+    };
+    let Some(KernelMemory {
+        mapper,
+        frame_allocator,
+    }) = kernel_memory.as_mut()
+    else {
+        return false;
+    };
+
+    let huge_page: Page<Size2MiB> = Page::containing_address(addr);
+    let covered = range.start <= huge_page.start_address()
+        && huge_page.start_address() + Size2MiB::SIZE <= range.end;
+    if covered && map_zeroed(huge_page, mapper, frame_allocator) {
+        return true;
+    }
+    map_zeroed(
+        Page::<Size4KiB>::containing_address(addr),
+        mapper,
+        frame_allocator,
+    )
+}
+
+/// Maps `page` to a newly allocated, zeroed frame. Returns false if there is no free frame or if
+/// the page (or a page table entry in its place) is already in use.
+fn map_zeroed<S: PageSize>(
+    page: Page<S>,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BuddyFrameAllocator,
+) -> bool
+where
+    OffsetPageTable<'static>: Mapper<S>,
+    BuddyFrameAllocator: FrameAllocator<S> + FrameDeallocator<S>,
+{
+    let Some(frame) = FrameAllocator::<S>::allocate_frame(frame_allocator) else {
+        return false;
+    };
+    // Frames can contain data from a previous owner, so zero them before handing them out
+    let frame_ptr: *mut u8 = (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { frame_ptr.write_bytes(0, S::SIZE as usize) };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            false
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use super::{
+    bitmap::BitmapFrameAllocator,
+    vmalloc::{self, Purpose, VirtRange, VmallocError},
+};
+use bootloader::bootinfo::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
+use core::{
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+use spin::Mutex;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{PageTableFlags, frame::PhysFrameRange},
+};
+
+/// Bytes of physical memory set aside for DMA buffers by `init`.
+pub const DMA_ZONE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Frames of DMA buffers come from here rather than from the kernel's frame allocator, which
+/// cannot hand out contiguous ranges of any length, and gets fragmented over time.
+static DMA_ZONE: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+/// Sets aside the lowest DMA_ZONE_SIZE bytes of usable memory for DMA buffers, and returns the
+/// memory map without them, for the kernel's frame allocator.
+///
+/// Taking the lowest memory means the zone also serves devices which only address 32 or 24 bits.
+///
+/// # Safety
+///
+/// The same requirements as for `BitmapFrameAllocator::init` apply. The frames of the zone must not
+/// be handed out by any other frame allocator, so only the returned map may be used for that.
+pub unsafe fn init(memory_map: &MemoryMap, physical_memory_offset: VirtAddr) -> MemoryMap {
+    let (zone, rest) = split_memory_map(memory_map, DMA_ZONE_SIZE);
+    let allocator = unsafe { BitmapFrameAllocator::init(&zone, physical_memory_offset) };
+    *DMA_ZONE.lock() = Some(allocator);
+    rest
+}
+
+/// Number of frames in the DMA zone which are available for buffers, or None before `init`.
+pub fn free_frames() -> Option<usize> {
+    DMA_ZONE
+        .lock()
+        .as_ref()
+        .map(BitmapFrameAllocator::free_frames)
+}
+
+/// Splits the lowest `size` bytes of usable memory off `memory_map`.
+///
+/// Returns a map of just those, and a copy of `memory_map` without them.
+fn split_memory_map(memory_map: &MemoryMap, size: u64) -> (MemoryMap, MemoryMap) {
+    let mut zone = MemoryMap::new();
+    let mut rest = MemoryMap::new();
+    let mut remaining = size / 4096;
+    for region in memory_map.iter().filter(|r| !r.range.is_empty()) {
+        if region.region_type != MemoryRegionType::Usable || remaining == 0 {
+            rest.add_region(*region);
+            continue;
+        }
+
+        let start = region.range.start_frame_number;
+        let end = region.range.end_frame_number;
+        let split = end.min(start + remaining);
+        zone.add_region(MemoryRegion {
+            range: FrameRange::new(start * 4096, split * 4096),
+            region_type: MemoryRegionType::Usable,
+        });
+        remaining -= split - start;
+        if split < end {
+            rest.add_region(MemoryRegion {
+                range: FrameRange::new(split * 4096, end * 4096),
+                region_type: MemoryRegionType::Usable,
+            });
+        }
+    }
+    (zone, rest)
+}
+
+/// A `T` in physically contiguous memory, for sharing with a device, e.g. a descriptor ring.
+///
+/// The memory is mapped write-back, like the physical memory mapping of the same frames, so that
+/// the two mappings never disagree on the memory type. DMA on x86 is cache coherent, so the device
+/// still sees every write without the cache being flushed. Fields the device writes to should still be `volatile::Volatile`, so that the compiler does not
+/// cache them either. The frames are freed on drop, so the device must be done with the buffer by
+/// then.
+pub struct DmaBuffer<T> {
+    ptr: NonNull<T>,
+    range: VirtRange,
+    frames: PhysFrameRange,
+}
+
+impl<T> DmaBuffer<T> {
+    /// Moves `value` into contiguous frames of the DMA zone. If `limit` is given, the frames lie
+    /// below that physical address, e.g. `bitmap::BELOW_4GIB` for devices with 32-bit DMA.
+    ///
+    /// Fails with NotInitialised before `init`, and with OutOfFrames if the zone has no such range.
+    ///
+    /// Panics if `T` needs more than page alignment.
+    pub fn new(value: T, limit: Option<PhysAddr>) -> Result<Self, VmallocError> {
+        assert!(mem::align_of::<T>() <= 4096, "DMA buffers are page aligned");
+        let size = mem::size_of::<T>().max(1);
+        let count = size.div_ceil(4096);
+
+        let frames = DMA_ZONE
+            .lock()
+            .as_mut()
+            .ok_or(VmallocError::NotInitialised)?
+            .allocate_contiguous(count, 4096, limit)
+            .ok_or(VmallocError::OutOfFrames)?;
+
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let phys_start = frames.start.start_address();
+        let range = match unsafe { vmalloc::map_phys(phys_start, size as u64, Purpose::Dma, flags) }
+        {
+            Ok(range) => range,
+            Err(err) => {
+                unsafe { release_frames(frames) };
+                return Err(err);
+            }
+        };
+
+        let ptr: *mut T = range.start.as_mut_ptr();
+        unsafe {
+            // frames can contain data from a previous owner, which the device must not see
+            ptr.cast::<u8>().write_bytes(0, range.size as usize);
+            ptr.write(value);
+        }
+        Ok(DmaBuffer {
+            ptr: NonNull::new(ptr).unwrap(),
+            range,
+            frames,
+        })
+    }
+
+    /// Address to program into the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.frames.start.start_address()
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.range.start
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+}
+
+/// Gives contiguous frames back to the DMA zone.
+///
+/// # Safety
+///
+/// The frames must not be mapped or in use by a device any more.
+unsafe fn release_frames(frames: PhysFrameRange) {
+    let mut zone = DMA_ZONE.lock();
+    unsafe { zone.as_mut().unwrap().deallocate_contiguous(frames) };
+}
+
+impl<T> Deref for DmaBuffer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for DmaBuffer<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.ptr.drop_in_place();
+            vmalloc::free(self.range.start).expect("DMA range was freed behind our back");
+            release_frames(self.frames);
+        }
+    }
+}
+
+#[cfg(test)]
+mod dma_zone {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn region(start: u64, end: u64, region_type: MemoryRegionType) -> MemoryRegion {
+        MemoryRegion {
+            range: FrameRange::new(start, end),
+            region_type,
+        }
+    }
+
+    #[test_case]
+    fn takes_lowest_usable_memory() {
+        let mut memory_map = MemoryMap::new();
+        memory_map.add_region(region(0, 4096, MemoryRegionType::FrameZero));
+        memory_map.add_region(region(4096, 2 * MIB, MemoryRegionType::Usable));
+        memory_map.add_region(region(2 * MIB, 3 * MIB, MemoryRegionType::Kernel));
+        memory_map.add_region(region(3 * MIB, 8 * MIB, MemoryRegionType::Usable));
+
+        let (zone, rest) = split_memory_map(&memory_map, 4 * MIB);
+        assert_eq!(zone.len(), 2);
+        assert_eq!(zone[0].range, FrameRange::new(4096, 2 * MIB));
+        assert_eq!(zone[1].range, FrameRange::new(3 * MIB, 5 * MIB + 4096));
+
+        assert_eq!(rest.len(), 3);
+        assert_eq!(rest[0].region_type, MemoryRegionType::FrameZero);
+        assert_eq!(rest[1].region_type, MemoryRegionType::Kernel);
+        assert_eq!(rest[2].range, FrameRange::new(5 * MIB + 4096, 8 * MIB));
+        assert_eq!(rest[2].region_type, MemoryRegionType::Usable);
+    }
+
+    #[test_case]
+    fn small_memory_goes_to_the_zone() {
+        let mut memory_map = MemoryMap::new();
+        memory_map.add_region(region(MIB, 2 * MIB, MemoryRegionType::Usable));
+
+        let (zone, rest) = split_memory_map(&memory_map, 4 * MIB);
+        assert_eq!(zone.len(), 1);
+        assert_eq!(rest.len(), 0);
+    }
+}
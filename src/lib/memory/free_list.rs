@@ -0,0 +1,166 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+};
+
+/// Written into the first bytes of every frame on the free list.
+struct FreeFrame {
+    next: Option<PhysFrame>,
+}
+
+/// A physical frame allocator that hands out frames from the bootloader memory map and reuses
+/// deallocated frames.
+///
+/// Frames that have never been allocated are taken from the usable regions of the memory map in
+/// order, by bumping a cursor. Deallocated frames are pushed onto a free list which is stored
+/// inside the free frames themselves (through the physical memory mapping), so no extra memory is
+/// needed to track them. Both allocation and deallocation are O(1).
+pub struct FreeListFrameAllocator {
+    memory_map: &'static MemoryMap,
+    physical_memory_offset: VirtAddr,
+    /// Index into `memory_map` of the region the cursor is currently in
+    region: usize,
+    /// Address of the next frame that has never been handed out
+    next_unused: u64,
+    /// Most recently deallocated frame
+    free_list: Option<PhysFrame>,
+}
+
+impl FreeListFrameAllocator {
+    /// Creates a frame allocator from the memory map passed by the bootloader.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the memory map is valid, i.e. all frames marked as `Usable`
+    /// are really unused, and that the complete physical memory is mapped at
+    /// `physical_memory_offset`. This method must only be called once.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let mut allocator = FreeListFrameAllocator {
+            memory_map,
+            physical_memory_offset,
+            region: 0,
+            next_unused: 0,
+            free_list: None,
+        };
+        allocator.seek_usable_region(0);
+        allocator
+    }
+
+    /// Moves the cursor to the start of the first usable region at or after index `from`.
+    fn seek_usable_region(&mut self, from: usize) {
+        self.region = from;
+        while let Some(region) = self.memory_map.get(self.region) {
+            if region.region_type == MemoryRegionType::Usable && !region.range.is_empty() {
+                self.next_unused = region.range.start_addr();
+                return;
+            }
+            self.region += 1;
+        }
+    }
+
+    /// Takes the next frame that has never been handed out.
+    fn allocate_unused(&mut self) -> Option<PhysFrame> {
+        let region = self.memory_map.get(self.region)?;
+        let frame = PhysFrame::containing_address(PhysAddr::new(self.next_unused));
+        self.next_unused += 4096;
+        if self.next_unused >= region.range.end_addr() {
+            self.seek_usable_region(self.region + 1);
+        }
+        Some(frame)
+    }
+
+    /// Returns a pointer to the FreeFrame header of `frame`.
+    fn free_frame_ptr(&self, frame: PhysFrame) -> *mut FreeFrame {
+        (self.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for FreeListFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        match self.free_list {
+            Some(frame) => {
+                // frames on the free list always contain a FreeFrame written by deallocate_frame
+                self.free_list = unsafe { self.free_frame_ptr(frame).read().next };
+                Some(frame)
+            }
+            None => self.allocate_unused(),
+        }
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for FreeListFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let free_frame = FreeFrame {
+            next: self.free_list.take(),
+        };
+        unsafe { self.free_frame_ptr(frame).write(free_frame) };
+        self.free_list = Some(frame);
+    }
+}
+
+#[cfg(test)]
+mod free_list_frame_allocator {
+    use super::*;
+    use crate::memory::test_utils::{TEST_FRAMES, TEST_MEMORY_MAP, test_memory_start};
+
+    fn new_test_allocator() -> FreeListFrameAllocator {
+        unsafe { FreeListFrameAllocator::init(&TEST_MEMORY_MAP, VirtAddr::new(0)) }
+    }
+
+    #[test_case]
+    fn allocates_frames_in_order() {
+        let mut allocator = new_test_allocator();
+        let start = test_memory_start();
+        for i in 0..TEST_FRAMES as u64 {
+            let frame = allocator
+                .allocate_frame()
+                .expect("frame should be available");
+            assert_eq!(frame.start_address().as_u64(), start + i * 4096);
+        }
+    }
+
+    #[test_case]
+    fn runs_out_of_frames() {
+        let mut allocator = new_test_allocator();
+        for _ in 0..TEST_FRAMES {
+            assert!(allocator.allocate_frame().is_some());
+        }
+        assert!(allocator.allocate_frame().is_none());
+    }
+
+    #[test_case]
+    fn deallocated_frames_are_reused() {
+        let mut allocator = new_test_allocator();
+        let frame_1 = allocator.allocate_frame().unwrap();
+        let frame_2 = allocator.allocate_frame().unwrap();
+
+        unsafe {
+            allocator.deallocate_frame(frame_1);
+            allocator.deallocate_frame(frame_2);
+        }
+
+        // most recently freed frame comes back first
+        assert_eq!(allocator.allocate_frame(), Some(frame_2));
+        assert_eq!(allocator.allocate_frame(), Some(frame_1));
+        assert_eq!(
+            allocator.allocate_frame().unwrap().start_address().as_u64(),
+            test_memory_start() + 2 * 4096,
+            "once the free list is empty, unused frames should be handed out again"
+        );
+    }
+
+    #[test_case]
+    fn exhausted_allocator_recovers_after_deallocation() {
+        let mut allocator = new_test_allocator();
+        let mut last = None;
+        while let Some(frame) = allocator.allocate_frame() {
+            last = Some(frame);
+        }
+        let last = last.unwrap();
+
+        unsafe { allocator.deallocate_frame(last) };
+        assert_eq!(allocator.allocate_frame(), Some(last));
+        assert!(allocator.allocate_frame().is_none());
+    }
+}
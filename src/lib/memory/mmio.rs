@@ -0,0 +1,216 @@
+use super::vmalloc::{self, Purpose, VirtRange, VmallocError};
+use core::{
+    arch::asm,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::{interrupts, tlb},
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::Msr,
+    },
+    structures::paging::PageTableFlags,
+};
+
+/// The IA32_PAT model specific register, which holds the memory type for each of the 8 PAT entries.
+const IA32_PAT: u32 = 0x277;
+/// Memory type encoding of write-combining in the PAT.
+const PAT_WRITE_COMBINING: u64 = 0x01;
+/// The PAT entry selected by NO_CACHE alone, which is uncached-minus after reset.
+const PAT_ENTRY_WC: u64 = 2;
+
+/// Reprograms PAT entry 2 from uncached-minus to write-combining, called by `memory::init`.
+///
+/// Bit 7 of a level 1 entry (which would select entries 4 to 7) is the same bit as HUGE_PAGE, which
+/// `OffsetPageTable` refuses on 4KiB pages, so write-combining has to replace one of the entries
+/// reachable through NO_CACHE and WRITE_THROUGH.
+///
+/// The PAT is changed as the Intel SDM (11.11.8) requires: with caching disabled, and with the
+/// caches and TLB flushed before and after, so that no stale memory type survives it.
+///
+/// # Safety
+///
+/// Must run once, before anything is mapped with NO_CACHE alone, and only while a single CPU is
+/// running (the PAT of every other CPU would have to be changed the same way).
+pub(super) unsafe fn init_pat() {
+    interrupts::without_interrupts(|| unsafe {
+        let cr0 = Cr0::read();
+        Cr0::write((cr0 | Cr0Flags::CACHE_DISABLE) - Cr0Flags::NOT_WRITE_THROUGH);
+        asm!("wbinvd", options(nostack, preserves_flags));
+        tlb::flush_all();
+
+        let mut pat = Msr::new(IA32_PAT);
+        let entries = pat.read() & !(0xff << (PAT_ENTRY_WC * 8));
+        pat.write(entries | PAT_WRITE_COMBINING << (PAT_ENTRY_WC * 8));
+
+        asm!("wbinvd", options(nostack, preserves_flags));
+        tlb::flush_all();
+        Cr0::write(cr0);
+    });
+}
+
+/// How the CPU caches accesses to an MMIO region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every access goes to the device, in program order. Right for registers, e.g. APIC or HPET.
+    Uncached,
+    /// Reads are cached, writes go to the device immediately.
+    WriteThrough,
+    /// Writes are buffered and may be combined or reordered. Right for framebuffers and
+    /// prefetchable PCI BARs.
+    WriteCombining,
+}
+
+impl CacheMode {
+    /// Returns the page table flags which select this mode, with the PAT set up by `memory::init`.
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining => PageTableFlags::NO_CACHE,
+        }
+    }
+}
+
+/// Device memory mapped into the vmalloc area, which is unmapped again on drop.
+///
+/// `T` describes the layout of the region, usually a `#[repr(C)]` struct of `volatile::Volatile`,
+/// `ReadOnly` and `WriteOnly` registers, like `vga_buffer::Buffer`. Regions without a fixed layout
+/// can use `Mmio<()>` together with `read` and `write`.
+pub struct Mmio<T> {
+    range: VirtRange,
+    phys_addr: PhysAddr,
+    /// Start of the region, which need not be page aligned
+    ptr: NonNull<u8>,
+    size: usize,
+    _marker: PhantomData<T>,
+}
+
+/// Maps `size` bytes of device memory starting at `phys_addr` with the given cache mode.
+///
+/// Panics if `size` is smaller than `T`, or if `phys_addr` is not aligned for `T`.
+///
+/// # Safety
+///
+/// The caller must guarantee that the physical range belongs to a device (not to RAM managed by a
+/// frame allocator), and that `T` matches the layout of its registers.
+pub unsafe fn map<T>(
+    phys_addr: PhysAddr,
+    size: usize,
+    cache: CacheMode,
+) -> Result<Mmio<T>, VmallocError> {
+    assert!(
+        size >= mem::size_of::<T>(),
+        "MMIO region smaller than its type"
+    );
+    assert!(
+        phys_addr.is_aligned(mem::align_of::<T>() as u64),
+        "MMIO region misaligned for its type"
+    );
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | cache.flags();
+    let range = unsafe { vmalloc::map_phys(phys_addr, size as u64, Purpose::Mmio, flags)? };
+    let virt_addr = range.start + phys_addr.as_u64() % 4096;
+    Ok(Mmio {
+        range,
+        phys_addr,
+        ptr: NonNull::new(virt_addr.as_mut_ptr()).unwrap(),
+        size,
+        _marker: PhantomData,
+    })
+}
+
+impl<T> Mmio<T> {
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.ptr.as_ptr())
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns a pointer to the value of type `U` at `offset` bytes into the region.
+    ///
+    /// Panics if it does not lie within the region or is misaligned.
+    fn field<U>(&self, offset: usize) -> *mut U {
+        assert!(
+            offset + mem::size_of::<U>() <= self.size,
+            "MMIO access at {offset:#x} out of bounds"
+        );
+        let ptr = unsafe { self.ptr.as_ptr().add(offset) }.cast::<U>();
+        assert!(ptr.is_aligned(), "misaligned MMIO access at {offset:#x}");
+        ptr
+    }
+
+    /// Reads the register of type `U` at `offset` bytes into the region, e.g. `read::<u32>(0x20)`
+    /// for the local APIC ID.
+    pub fn read<U: Copy>(&self, offset: usize) -> U {
+        unsafe { self.field::<U>(offset).read_volatile() }
+    }
+
+    /// Writes the register of type `U` at `offset` bytes into the region.
+    pub fn write<U: Copy>(&mut self, offset: usize, value: U) {
+        unsafe { self.field::<U>(offset).write_volatile(value) }
+    }
+}
+
+impl<T> Deref for Mmio<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.cast::<T>().as_ref() }
+    }
+}
+
+impl<T> DerefMut for Mmio<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.cast::<T>().as_mut() }
+    }
+}
+
+impl<T> Drop for Mmio<T> {
+    fn drop(&mut self) {
+        // nothing can access the region after this, as it is only reachable through self
+        unsafe { vmalloc::free(self.range.start) }.expect("MMIO range was freed behind our back");
+    }
+}
+
+#[cfg(test)]
+mod cache_mode {
+    use super::*;
+
+    /// The PAT entry a level 1 entry selects with PWT as bit 0 and PCD as bit 1.
+    fn pat_entry(flags: PageTableFlags) -> u8 {
+        flags.contains(PageTableFlags::WRITE_THROUGH) as u8
+            | (flags.contains(PageTableFlags::NO_CACHE) as u8) << 1
+    }
+
+    #[test_case]
+    fn selects_pat_entries() {
+        // entries 1 and 3 are write-through and uncached after reset
+        assert_eq!(pat_entry(CacheMode::WriteThrough.flags()), 1);
+        assert_eq!(pat_entry(CacheMode::Uncached.flags()), 3);
+        assert_eq!(
+            pat_entry(CacheMode::WriteCombining.flags()) as u64,
+            PAT_ENTRY_WC
+        );
+    }
+
+    #[test_case]
+    fn never_sets_pat_bit() {
+        for mode in [
+            CacheMode::Uncached,
+            CacheMode::WriteThrough,
+            CacheMode::WriteCombining,
+        ] {
+            assert!(!mode.flags().contains(PageTableFlags::HUGE_PAGE));
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use super::KERNEL_MEMORY;
+use core::fmt;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{PageTable, PageTableFlags, page_table::PageTableEntry},
+};
+
+/// Flags which every level of the hierarchy has to allow for them to take effect on a page.
+const INHERITED_FLAGS: PageTableFlags =
+    PageTableFlags::WRITABLE.union(PageTableFlags::USER_ACCESSIBLE);
+
+/// Flags the CPU sets on its own, which are ignored when merging pages into ranges.
+const CPU_FLAGS: PageTableFlags = PageTableFlags::ACCESSED.union(PageTableFlags::DIRTY);
+
+/// Size of the page a virtual address is mapped with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl MappingSize {
+    pub const fn bytes(self) -> u64 {
+        match self {
+            MappingSize::Size4KiB => 4096,
+            MappingSize::Size2MiB => 2 * 1024 * 1024,
+            MappingSize::Size1GiB => 1024 * 1024 * 1024,
+        }
+    }
+
+    /// Returns the size of the page an entry maps, or None if it points to the next level table.
+    ///
+    /// `level` is 4 for the level 4 table and 1 for a level 1 table.
+    fn of_entry(level: u8, flags: PageTableFlags) -> Option<Self> {
+        let huge = flags.contains(PageTableFlags::HUGE_PAGE);
+        match level {
+            1 => Some(MappingSize::Size4KiB),
+            2 if huge => Some(MappingSize::Size2MiB),
+            3 if huge => Some(MappingSize::Size1GiB),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MappingSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MappingSize::Size4KiB => write!(f, "4KiB"),
+            MappingSize::Size2MiB => write!(f, "2MiB"),
+            MappingSize::Size1GiB => write!(f, "1GiB"),
+        }
+    }
+}
+
+/// Where a virtual address is mapped to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    /// Start of the frame backing the page
+    pub frame: PhysAddr,
+    /// The translated address, i.e. `frame` plus the offset into the page
+    pub phys_addr: PhysAddr,
+    pub size: MappingSize,
+    /// Flags in effect for the page. The page is only writable or user accessible if every level
+    /// of the hierarchy allows it, and it is not executable if any level sets NO_EXECUTE.
+    pub flags: PageTableFlags,
+}
+
+/// A run of pages of the same size which are mapped to contiguous frames with the same flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    /// Length in bytes
+    pub len: u64,
+    pub phys_start: PhysAddr,
+    pub size: MappingSize,
+    /// Flags in effect for the pages, without ACCESSED and DIRTY
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    /// Returns whether `next` continues this range, both virtually and physically.
+    fn is_continued_by(&self, next: &MappedRange) -> bool {
+        self.start.as_u64().wrapping_add(self.len) == next.start.as_u64()
+            && self.phys_start + self.len == next.phys_start
+            && self.size == next.size
+            && self.flags == next.flags
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let shown_flags = self.flags - PageTableFlags::PRESENT - PageTableFlags::HUGE_PAGE;
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x} {:>5} x {} {:?}",
+            self.start.as_u64(),
+            self.start.as_u64().wrapping_add(self.len),
+            self.phys_start.as_u64(),
+            self.len / self.size.bytes(),
+            self.size,
+            shown_flags
+        )
+    }
+}
+
+/// Number of frames used by a page table hierarchy, per level.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TableFrames {
+    pub level_4: usize,
+    pub level_3: usize,
+    pub level_2: usize,
+    pub level_1: usize,
+}
+
+impl TableFrames {
+    pub fn total(&self) -> usize {
+        self.level_4 + self.level_3 + self.level_2 + self.level_1
+    }
+}
+
+/// Reads a page table hierarchy without modifying it, unlike the Mapper implementations.
+pub struct PageTableWalker<'a> {
+    level_4_table: &'a PageTable,
+    phys_offset: VirtAddr,
+}
+
+impl<'a> PageTableWalker<'a> {
+    /// # Safety
+    ///
+    /// The caller must guarantee that the complete physical memory is mapped at `phys_offset`, and
+    /// that every present entry reachable from `level_4_table` points to a valid page table or
+    /// page. The tables must not change while the walker exists.
+    pub unsafe fn new(level_4_table: &'a PageTable, phys_offset: VirtAddr) -> Self {
+        PageTableWalker {
+            level_4_table,
+            phys_offset,
+        }
+    }
+
+    /// Returns the next level table an entry points to.
+    fn next_table(&self, entry: &PageTableEntry) -> &'a PageTable {
+        let virt = self.phys_offset + entry.addr().as_u64();
+        unsafe { &*virt.as_ptr() }
+    }
+
+    /// Returns what `addr` is mapped to, or None if it is not mapped.
+    pub fn translate(&self, addr: VirtAddr) -> Option<Translation> {
+        let indices = [
+            addr.p4_index(),
+            addr.p3_index(),
+            addr.p2_index(),
+            addr.p1_index(),
+        ];
+
+        let mut table = self.level_4_table;
+        let mut flags = INHERITED_FLAGS;
+        for (level, index) in (1..=4).rev().zip(indices) {
+            let entry = &table[index];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            flags = effective_flags(flags, entry.flags());
+            if let Some(size) = MappingSize::of_entry(level, entry.flags()) {
+                let page = translation(entry, size, flags);
+                let offset = addr.as_u64() & (size.bytes() - 1);
+                return Some(Translation {
+                    phys_addr: page.frame + offset,
+                    ..page
+                });
+            }
+            table = self.next_table(entry);
+        }
+        unreachable!("level 1 entries always map a page")
+    }
+
+    /// Calls `f` with the start address of every mapped page, in ascending order.
+    pub fn for_each_page(&self, mut f: impl FnMut(VirtAddr, Translation)) {
+        self.walk(self.level_4_table, 4, 0, INHERITED_FLAGS, &mut f);
+    }
+
+    fn walk(
+        &self,
+        table: &PageTable,
+        level: u8,
+        base: u64,
+        parent_flags: PageTableFlags,
+        f: &mut impl FnMut(VirtAddr, Translation),
+    ) {
+        let present = table
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.flags().contains(PageTableFlags::PRESENT));
+        for (index, entry) in present {
+            let start = base | (index as u64) << (12 + 9 * (level - 1));
+            let flags = effective_flags(parent_flags, entry.flags());
+            match MappingSize::of_entry(level, entry.flags()) {
+                Some(size) => f(
+                    VirtAddr::new_truncate(start),
+                    translation(entry, size, flags),
+                ),
+                None => self.walk(self.next_table(entry), level - 1, start, flags, f),
+            }
+        }
+    }
+
+    /// Calls `f` for every run of contiguously mapped pages with the same flags, in ascending
+    /// order.
+    pub fn for_each_range(&self, mut f: impl FnMut(MappedRange)) {
+        let mut current: Option<MappedRange> = None;
+        self.for_each_page(|start, page| {
+            let range = MappedRange {
+                start,
+                len: page.size.bytes(),
+                phys_start: page.frame,
+                size: page.size,
+                flags: page.flags - CPU_FLAGS,
+            };
+            match &mut current {
+                Some(current) if current.is_continued_by(&range) => current.len += range.len,
+                _ => {
+                    if let Some(done) = current.replace(range) {
+                        f(done);
+                    }
+                }
+            }
+        });
+        if let Some(done) = current {
+            f(done);
+        }
+    }
+
+    /// Writes every mapped range to `w`, one per line.
+    pub fn dump(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        let mut result = Ok(());
+        self.for_each_range(|range| {
+            if result.is_ok() {
+                result = writeln!(w, "{range}");
+            }
+        });
+        result
+    }
+
+    /// Counts the frames used by the page tables themselves.
+    pub fn table_frames(&self) -> TableFrames {
+        let mut frames = TableFrames {
+            level_4: 1,
+            ..TableFrames::default()
+        };
+        self.count_tables(self.level_4_table, 4, &mut frames);
+        frames
+    }
+
+    fn count_tables(&self, table: &PageTable, level: u8, frames: &mut TableFrames) {
+        let tables = table.iter().filter(|entry| {
+            entry.flags().contains(PageTableFlags::PRESENT)
+                && MappingSize::of_entry(level, entry.flags()).is_none()
+        });
+        for entry in tables {
+            match level {
+                4 => frames.level_3 += 1,
+                3 => frames.level_2 += 1,
+                _ => frames.level_1 += 1,
+            }
+            self.count_tables(self.next_table(entry), level - 1, frames);
+        }
+    }
+}
+
+/// Combines the flags of an entry with those in effect for the table it lies in.
+fn effective_flags(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let mut flags = (entry - INHERITED_FLAGS) | (entry & parent & INHERITED_FLAGS);
+    flags |= parent & PageTableFlags::NO_EXECUTE;
+    flags
+}
+
+fn translation(entry: &PageTableEntry, size: MappingSize, flags: PageTableFlags) -> Translation {
+    // the PAT bit of huge pages lies within the address bits of the entry
+    let frame = PhysAddr::new(entry.addr().as_u64() & !(size.bytes() - 1));
+    let flags = match size {
+        MappingSize::Size4KiB => flags,
+        // on level 1 entries, the same bit selects the PAT entry, so it is only removed here
+        _ => flags - PageTableFlags::HUGE_PAGE,
+    };
+    Translation {
+        frame,
+        phys_addr: frame,
+        size,
+        flags,
+    }
+}
+
+/// Runs `f` with a walker over the active page tables. Returns None if the kernel memory has not
+/// been initialised yet.
+///
+/// KERNEL_MEMORY stays locked while `f` runs, so `f` must not allocate on the heap (see KERNEL_MEMORY).
+pub fn with_active_tables<R>(f: impl FnOnce(&PageTableWalker) -> R) -> Option<R> {
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    let mapper = &mut kernel_memory.as_mut()?.mapper;
+    let phys_offset = mapper.phys_offset();
+    let walker = unsafe { PageTableWalker::new(mapper.level_4_table(), phys_offset) };
+    Some(f(&walker))
+}
+
+/// Translates `addr` with the active page tables.
+pub fn translate(addr: VirtAddr) -> Option<Translation> {
+    with_active_tables(|walker| walker.translate(addr)).flatten()
+}
+
+/// Writes every range mapped by the active page tables to `w`, e.g.
+/// `page_tables::dump(&mut *SERIAL1.lock())`.
+pub fn dump(w: &mut impl fmt::Write) -> fmt::Result {
+    with_active_tables(|walker| walker.dump(w)).unwrap_or(Ok(()))
+}
+
+/// Counts the frames used by the active page tables.
+pub fn table_frames() -> Option<TableFrames> {
+    with_active_tables(|walker| walker.table_frames())
+}
+
+#[cfg(test)]
+mod page_table_walker {
+    use super::*;
+
+    /// Backs the tables of the test hierarchy. With a physical memory offset of 0, the "physical"
+    /// address of a table is its virtual address.
+    #[repr(align(4096))]
+    struct Tables([PageTable; 4]);
+
+    static mut TABLES: Tables = Tables([const { PageTable::new() }; 4]);
+
+    const TABLE: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+    const HUGE: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::HUGE_PAGE);
+
+    /// Builds a hierarchy which maps
+    /// - 0x1000..0x3000 to 0x5000, and 0x3000 to 0x8000 with 4KiB pages, which are not executable
+    ///   because their level 2 entry sets NO_EXECUTE
+    /// - 0x20_0000 to 0x40_0000 with a read-only 2MiB page
+    /// - 0x4000_0000 to 0x8000_0000 with a writable 1GiB page
+    fn walker() -> PageTableWalker<'static> {
+        let tables = &raw mut TABLES;
+        let [l4, l3, l2, l1] = unsafe { &mut (*tables).0 };
+        for table in [&mut *l4, &mut *l3, &mut *l2, &mut *l1] {
+            table.zero();
+        }
+        let phys = |table: &PageTable| PhysAddr::new(table as *const PageTable as u64);
+
+        l1[1].set_addr(PhysAddr::new(0x5000), TABLE);
+        l1[2].set_addr(PhysAddr::new(0x6000), TABLE);
+        l1[3].set_addr(PhysAddr::new(0x8000), TABLE);
+        l2[0].set_addr(phys(l1), TABLE | PageTableFlags::NO_EXECUTE);
+        l2[1].set_addr(PhysAddr::new(0x40_0000), HUGE);
+        l3[0].set_addr(phys(l2), TABLE);
+        l3[1].set_addr(PhysAddr::new(0x8000_0000), HUGE | PageTableFlags::WRITABLE);
+        l4[0].set_addr(phys(l3), TABLE);
+
+        unsafe { PageTableWalker::new(l4, VirtAddr::new(0)) }
+    }
+
+    #[test_case]
+    fn translates_4kib_page() {
+        let page = walker().translate(VirtAddr::new(0x2123)).unwrap();
+        assert_eq!(page.frame, PhysAddr::new(0x6000));
+        assert_eq!(page.phys_addr, PhysAddr::new(0x6123));
+        assert_eq!(page.size, MappingSize::Size4KiB);
+    }
+
+    #[test_case]
+    fn translates_huge_pages() {
+        let walker = walker();
+
+        let page = walker.translate(VirtAddr::new(0x21_2345)).unwrap();
+        assert_eq!(page.frame, PhysAddr::new(0x40_0000));
+        assert_eq!(page.phys_addr, PhysAddr::new(0x41_2345));
+        assert_eq!(page.size, MappingSize::Size2MiB);
+
+        let page = walker.translate(VirtAddr::new(0x7fff_ffff)).unwrap();
+        assert_eq!(page.phys_addr, PhysAddr::new(0xbfff_ffff));
+        assert_eq!(page.size, MappingSize::Size1GiB);
+    }
+
+    #[test_case]
+    fn unmapped_addresses() {
+        let walker = walker();
+        assert_eq!(walker.translate(VirtAddr::new(0x0)), None);
+        assert_eq!(walker.translate(VirtAddr::new(0x4000)), None);
+        assert_eq!(walker.translate(VirtAddr::new(0x8000_0000)), None);
+        assert_eq!(walker.translate(VirtAddr::new(0x80_0000_0000)), None);
+    }
+
+    #[test_case]
+    fn flags_are_combined_across_levels() {
+        let walker = walker();
+
+        let flags = walker.translate(VirtAddr::new(0x1000)).unwrap().flags;
+        assert!(flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+
+        let flags = walker.translate(VirtAddr::new(0x20_0000)).unwrap().flags;
+        assert_eq!(flags, PageTableFlags::PRESENT);
+
+        let flags = walker.translate(VirtAddr::new(0x4000_0000)).unwrap().flags;
+        assert_eq!(flags, TABLE);
+    }
+
+    #[test_case]
+    fn contiguous_pages_are_merged() {
+        let mut ranges = [None; 8];
+        let mut count = 0;
+        walker().for_each_range(|range| {
+            ranges[count] = Some((range.start.as_u64(), range.len, range.phys_start.as_u64()));
+            count += 1;
+        });
+
+        assert_eq!(count, 4);
+        assert_eq!(ranges[0], Some((0x1000, 0x2000, 0x5000)));
+        assert_eq!(ranges[1], Some((0x3000, 0x1000, 0x8000)));
+        assert_eq!(ranges[2], Some((0x20_0000, 0x20_0000, 0x40_0000)));
+        assert_eq!(ranges[3], Some((0x4000_0000, 0x4000_0000, 0x8000_0000)));
+    }
+
+    #[test_case]
+    fn counts_table_frames() {
+        let frames = walker().table_frames();
+        assert_eq!(
+            frames,
+            TableFrames {
+                level_4: 1,
+                level_3: 1,
+                level_2: 1,
+                level_1: 1,
+            }
+        );
+        assert_eq!(frames.total(), 4);
+    }
+}
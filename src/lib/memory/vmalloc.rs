@@ -0,0 +1,430 @@
+use super::{KERNEL_MEMORY, KernelMemory, buddy::BuddyFrameAllocator};
+use spin::Mutex;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+        mapper::MapToError,
+    },
+};
+
+/// Start of the part of the higher half which kernel virtual ranges are handed out from.
+pub const VMALLOC_START: u64 = 0xffff_c000_0000_0000;
+/// Size of the vmalloc area, 1 TiB
+pub const VMALLOC_SIZE: u64 = 1 << 40;
+
+/// Unmapped gap left after every range, so that running off the end of one (e.g. a stack
+/// overflow) faults instead of silently corrupting the next.
+pub const GUARD_SIZE: u64 = 4096;
+
+/// Maximum number of ranges which can be allocated at the same time.
+const MAX_RANGES: usize = 64;
+
+/// What a virtual range is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    Heap,
+    Stack,
+    Mmio,
+    Framebuffer,
+    /// Memory shared with devices, see `dma::DmaBuffer`
+    Dma,
+}
+
+/// A virtual range handed out by the vmalloc area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtRange {
+    pub start: VirtAddr,
+    /// Size in bytes, a multiple of the page size
+    pub size: u64,
+    pub purpose: Purpose,
+    /// Whether the frames behind the range were allocated by `map`, and get freed with it
+    pub owns_frames: bool,
+}
+
+impl VirtRange {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let first = Page::containing_address(self.start);
+        (0..self.size / 4096).map(move |i| first + i)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VmallocError {
+    /// No gap in the vmalloc area is large enough
+    OutOfVirtualSpace,
+    /// All MAX_RANGES slots are in use
+    TooManyRanges,
+    /// No range starts at the given address
+    NotAllocated,
+    /// The kernel memory has not been initialised yet
+    NotInitialised,
+    /// There were no frames left for the range or its page tables
+    OutOfFrames,
+    /// Part of the range was mapped already, which means some other code maps into the vmalloc
+    /// area behind its back
+    AlreadyMapped,
+}
+
+impl From<MapToError<Size4KiB>> for VmallocError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => VmallocError::OutOfFrames,
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => {
+                VmallocError::AlreadyMapped
+            }
+        }
+    }
+}
+
+/// Keeps track of which parts of a virtual area are in use, without touching any page tables.
+pub struct VirtualRangeAllocator {
+    area_start: u64,
+    area_end: u64,
+    ranges: [Option<VirtRange>; MAX_RANGES],
+}
+
+impl VirtualRangeAllocator {
+    /// Creates an allocator for `start..start + size`, which must be page aligned.
+    pub const fn new(start: u64, size: u64) -> Self {
+        VirtualRangeAllocator {
+            area_start: start,
+            area_end: start + size,
+            ranges: [None; MAX_RANGES],
+        }
+    }
+
+    /// Finds the lowest gap of at least `size` bytes (rounded up to whole pages), with GUARD_SIZE
+    /// bytes to either neighbour.
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        purpose: Purpose,
+        owns_frames: bool,
+    ) -> Result<VirtRange, VmallocError> {
+        let size = size
+            .checked_next_multiple_of(4096)
+            .filter(|&size| size > 0)
+            .ok_or(VmallocError::OutOfVirtualSpace)?;
+        let slot = self
+            .ranges
+            .iter()
+            .position(|r| r.is_none())
+            .ok_or(VmallocError::TooManyRanges)?;
+
+        let mut start = self.area_start;
+        // every range which is in the way moves the candidate behind it, so this ends after at
+        // most MAX_RANGES rounds
+        loop {
+            let end = start
+                .checked_add(size)
+                .filter(|&end| end <= self.area_end)
+                .ok_or(VmallocError::OutOfVirtualSpace)?;
+            let Some(blocking) = self.ranges.iter().flatten().find(|r| {
+                let r_start = r.start.as_u64();
+                let r_end = r_start + r.size;
+                r_start < end.saturating_add(GUARD_SIZE) && start < r_end.saturating_add(GUARD_SIZE)
+            }) else {
+                break;
+            };
+            start = blocking.end().as_u64().saturating_add(GUARD_SIZE);
+        }
+
+        let range = VirtRange {
+            start: VirtAddr::new(start),
+            size,
+            purpose,
+            owns_frames,
+        };
+        self.ranges[slot] = Some(range);
+        Ok(range)
+    }
+
+    /// Removes the range starting at `start`, and returns it.
+    pub fn free(&mut self, start: VirtAddr) -> Result<VirtRange, VmallocError> {
+        self.ranges
+            .iter_mut()
+            .find(|r| r.is_some_and(|r| r.start == start))
+            .and_then(Option::take)
+            .ok_or(VmallocError::NotAllocated)
+    }
+
+    /// Returns the range which contains `addr`, if any.
+    pub fn find(&self, addr: VirtAddr) -> Option<VirtRange> {
+        self.ranges
+            .iter()
+            .flatten()
+            .find(|r| r.contains(addr))
+            .copied()
+    }
+
+    /// Returns the allocated ranges, in no particular order.
+    pub fn ranges(&self) -> impl Iterator<Item = &VirtRange> {
+        self.ranges.iter().flatten()
+    }
+}
+
+static VMALLOC: Mutex<VirtualRangeAllocator> =
+    Mutex::new(VirtualRangeAllocator::new(VMALLOC_START, VMALLOC_SIZE));
+
+/// Hands out a virtual range without mapping anything, for callers which map its pages themselves.
+///
+/// The page fault handler does not back these ranges, demand paged ones (like the heap) are
+/// registered with `demand_paging::reserve` instead. Pages the caller maps into it are unmapped by
+/// `free`, but their frames are not freed.
+pub fn reserve(size: u64, purpose: Purpose) -> Result<VirtRange, VmallocError> {
+    VMALLOC.lock().allocate(size, purpose, false)
+}
+
+/// Hands out a virtual range and backs it with fresh, zeroed frames, which `free` gives back to
+/// the frame allocator.
+pub fn map(size: u64, purpose: Purpose, flags: PageTableFlags) -> Result<VirtRange, VmallocError> {
+    let range = VMALLOC.lock().allocate(size, purpose, true)?;
+    map_range(range, flags, |frame_allocator| {
+        frame_allocator.allocate_frame()
+    })?;
+    Ok(range)
+}
+
+/// Hands out a virtual range and maps it to the physical range starting at `phys_start`, e.g. for
+/// MMIO or a framebuffer. The frames are left alone by `free`.
+///
+/// `phys_start` is rounded down to a page boundary, so the returned range starts at the page
+/// containing it.
+///
+/// # Safety
+///
+/// The caller must guarantee that mapping the physical range with `flags` cannot break memory
+/// safety, e.g. that it is not in use by a frame allocator.
+pub unsafe fn map_phys(
+    phys_start: PhysAddr,
+    size: u64,
+    purpose: Purpose,
+    flags: PageTableFlags,
+) -> Result<VirtRange, VmallocError> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys_start);
+    let size = size + (phys_start - first_frame.start_address());
+    let range = VMALLOC.lock().allocate(size, purpose, false)?;
+    let mut next_frame = first_frame;
+    map_range(range, flags, |_| {
+        let frame = next_frame;
+        next_frame += 1;
+        Some(frame)
+    })?;
+    Ok(range)
+}
+
+/// Maps every page of a freshly allocated range to the frames returned by `next_frame`. On failure
+/// everything mapped so far is undone and the range is freed.
+fn map_range(
+    range: VirtRange,
+    flags: PageTableFlags,
+    mut next_frame: impl FnMut(&mut BuddyFrameAllocator) -> Option<PhysFrame>,
+) -> Result<(), VmallocError> {
+    let mut guard = KERNEL_MEMORY.lock();
+    let Some(kernel_memory) = guard.as_mut() else {
+        drop(guard);
+        VMALLOC.lock().free(range.start)?;
+        return Err(VmallocError::NotInitialised);
+    };
+
+    for page in range.pages() {
+        let mapped = next_frame(&mut kernel_memory.frame_allocator)
+            .ok_or(VmallocError::OutOfFrames)
+            .and_then(|frame| map_page(kernel_memory, &range, page, frame, flags));
+        if let Err(err) = mapped {
+            unmap_range(range, kernel_memory);
+            drop(guard);
+            VMALLOC.lock().free(range.start)?;
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Maps `page` of `range` to `frame`, zeroing the frame first if the range owns it.
+fn map_page(
+    kernel_memory: &mut KernelMemory,
+    range: &VirtRange,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), VmallocError> {
+    let KernelMemory {
+        mapper,
+        frame_allocator,
+    } = kernel_memory;
+    if range.owns_frames {
+        // frames can contain data from a previous owner
+        let frame_ptr: *mut u8 =
+            (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe { frame_ptr.write_bytes(0, 4096) };
+    }
+
+    match unsafe {
+        mapper.map_to(
+            page,
+            frame,
+            flags | PageTableFlags::PRESENT,
+            frame_allocator,
+        )
+    } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            if range.owns_frames {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+            Err(err.into())
+        }
+    }
+}
+
+/// Unmaps every mapped page of `range`, freeing the frames if the range owns them.
+fn unmap_range(range: VirtRange, kernel_memory: &mut KernelMemory) {
+    let KernelMemory {
+        mapper,
+        frame_allocator,
+    } = kernel_memory;
+    for page in range.pages() {
+        // pages of reserved ranges need not be mapped
+        let Ok((frame, flush)) = mapper.unmap(page) else {
+            continue;
+        };
+        flush.flush();
+        if range.owns_frames {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+/// Unmaps the range starting at `start` and gives it back, along with its frames if it was
+/// created by `map`.
+///
+/// # Safety
+///
+/// The caller must guarantee that the range is no longer accessed.
+pub unsafe fn free(start: VirtAddr) -> Result<VirtRange, VmallocError> {
+    let range = VMALLOC
+        .lock()
+        .find(start)
+        .filter(|r| r.start == start)
+        .ok_or(VmallocError::NotAllocated)?;
+
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    let kernel_memory = kernel_memory.as_mut().ok_or(VmallocError::NotInitialised)?;
+    unmap_range(range, kernel_memory);
+
+    VMALLOC.lock().free(start)
+}
+
+/// Returns the vmalloc range which contains `addr`, if any.
+pub fn find(addr: VirtAddr) -> Option<VirtRange> {
+    VMALLOC.lock().find(addr)
+}
+
+#[cfg(test)]
+mod virtual_range_allocator {
+    use super::*;
+
+    const START: u64 = 0x1000_0000;
+
+    fn new_test_allocator() -> VirtualRangeAllocator {
+        VirtualRangeAllocator::new(START, 16 * 4096)
+    }
+
+    #[test_case]
+    fn ranges_are_separated_by_guard_pages() {
+        let mut allocator = new_test_allocator();
+        let a = allocator.allocate(4096, Purpose::Heap, true).unwrap();
+        let b = allocator.allocate(100, Purpose::Stack, true).unwrap();
+        assert_eq!(a.start.as_u64(), START);
+        assert_eq!(b.start, a.end() + GUARD_SIZE);
+        assert_eq!(b.size, 4096);
+        assert_eq!(b.purpose, Purpose::Stack);
+    }
+
+    #[test_case]
+    fn freed_range_is_reused() {
+        let mut allocator = new_test_allocator();
+        let a = allocator.allocate(2 * 4096, Purpose::Heap, true).unwrap();
+        allocator.allocate(4096, Purpose::Heap, true).unwrap();
+        assert_eq!(allocator.free(a.start), Ok(a));
+        let c = allocator.allocate(4096, Purpose::Mmio, false).unwrap();
+        assert_eq!(c.start, a.start);
+    }
+
+    #[test_case]
+    fn skips_gaps_which_are_too_small() {
+        let mut allocator = new_test_allocator();
+        let a = allocator.allocate(4096, Purpose::Heap, true).unwrap();
+        let b = allocator.allocate(4096, Purpose::Heap, true).unwrap();
+        allocator.free(a.start).unwrap();
+        let c = allocator.allocate(2 * 4096, Purpose::Heap, true).unwrap();
+        assert_eq!(c.start, b.end() + GUARD_SIZE);
+    }
+
+    #[test_case]
+    fn out_of_virtual_space() {
+        let mut allocator = new_test_allocator();
+        allocator.allocate(8 * 4096, Purpose::Heap, true).unwrap();
+        assert_eq!(
+            allocator.allocate(8 * 4096, Purpose::Heap, true),
+            Err(VmallocError::OutOfVirtualSpace)
+        );
+        assert_eq!(
+            allocator.allocate(0, Purpose::Heap, true),
+            Err(VmallocError::OutOfVirtualSpace)
+        );
+        assert!(allocator.allocate(7 * 4096, Purpose::Heap, true).is_ok());
+    }
+
+    #[test_case]
+    fn huge_sizes_do_not_overflow() {
+        let mut allocator = new_test_allocator();
+        allocator.allocate(4096, Purpose::Heap, true).unwrap();
+        for size in [u64::MAX, u64::MAX - 2 * 4096, u64::MAX - START] {
+            assert_eq!(
+                allocator.allocate(size, Purpose::Heap, true),
+                Err(VmallocError::OutOfVirtualSpace)
+            );
+        }
+    }
+
+    #[test_case]
+    fn too_many_ranges() {
+        let mut allocator = VirtualRangeAllocator::new(START, 4 * MAX_RANGES as u64 * 4096);
+        for _ in 0..MAX_RANGES {
+            allocator.allocate(4096, Purpose::Stack, true).unwrap();
+        }
+        assert_eq!(
+            allocator.allocate(4096, Purpose::Stack, true),
+            Err(VmallocError::TooManyRanges)
+        );
+    }
+
+    #[test_case]
+    fn find_and_free_unknown_range() {
+        let mut allocator = new_test_allocator();
+        let a = allocator
+            .allocate(2 * 4096, Purpose::Framebuffer, false)
+            .unwrap();
+        assert_eq!(allocator.find(a.start + 4096u64), Some(a));
+        assert_eq!(allocator.find(a.end()), None);
+        assert_eq!(
+            allocator.free(a.start + 4096u64),
+            Err(VmallocError::NotAllocated)
+        );
+        assert_eq!(allocator.ranges().count(), 1);
+    }
+}
@@ -4,6 +4,8 @@
 #![test_runner(crate::test_utils::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(allocator_api)]
+#![feature(alloc_error_handler)]
 
 pub mod allocator;
 pub mod gdt;
@@ -2,7 +2,7 @@ use crate::{
     qemu::{QemuExitCode, exit_qemu},
     serial_println,
 };
-use core::panic::PanicInfo;
+use core::{fmt::Write, panic::PanicInfo};
 
 pub trait Testable {
     fn run(&self);
@@ -33,3 +33,30 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     exit_qemu(QemuExitCode::Failed);
     crate::hlt_loop();
 }
+
+/// Returns whether the message of a panic contains `needle`, for tests which expect a particular
+/// panic. Only the first 1 KiB of the message is searched, as there is no heap to format it into.
+pub fn panic_message_contains(info: &PanicInfo, needle: &str) -> bool {
+    struct Buffer {
+        bytes: [u8; 1024],
+        len: usize,
+    }
+
+    impl Write for Buffer {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            let n = s.len().min(self.bytes.len() - self.len);
+            self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+            self.len += n;
+            Ok(())
+        }
+    }
+
+    let mut buffer = Buffer {
+        bytes: [0; 1024],
+        len: 0,
+    };
+    let _ = write!(buffer, "{}", info.message());
+    buffer.bytes[..buffer.len]
+        .windows(needle.len())
+        .any(|window| window == needle.as_bytes())
+}
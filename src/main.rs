@@ -15,7 +15,10 @@
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use bootloader::{BootInfo, entry_point};
 use core::panic::PanicInfo;
-use hypoxide::{allocator, println};
+use hypoxide::{
+    allocator::{self, HeapSizing},
+    println,
+};
 
 extern crate alloc;
 
@@ -30,11 +33,19 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     hypoxide::init();
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let memory_map = unsafe { memory::dma::init(&boot_info.memory_map, phys_mem_offset) };
+    let frame_allocator =
+        unsafe { memory::buddy::BuddyFrameAllocator::init(&memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    let heap_size = allocator::init_heap_from_memory_map(&memory_map, HeapSizing::DEFAULT)
+        .expect("heap initialization failed");
+    println!(
+        "heap: {} KiB of {} KiB usable memory",
+        heap_size / 1024,
+        memory::usable_memory(&memory_map) / 1024
+    );
 
     let x = Box::new(41);
     println!("x at {:p}", x);
@@ -0,0 +1,303 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hypoxide::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use hypoxide::{
+    allocator::{
+        Locked,
+        bump::BumpAllocator,
+        debug::DebugAllocator,
+        external_linked_list::ExternalLinkedListAllocator,
+        fixed_size_block::FixedSizeBlockAllocator,
+        linked_list::{FitPolicy, LinkedListAllocator},
+        stats::AllocatorStats,
+        tlsf::TlsfAllocator,
+        validate::Validate,
+    },
+    serial_println,
+};
+
+entry_point!(main);
+
+/// Set at compile time to reproduce a failed run, e.g.
+/// `STRESS_SEED=1234 cargo test --test allocator_stress`
+const FIXED_SEED: Option<&str> = option_env!("STRESS_SEED");
+
+/// Seed of the test which is currently running, printed if it fails.
+static SEED: AtomicU64 = AtomicU64::new(0);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    hypoxide::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let seed = SEED.load(Ordering::Relaxed);
+    serial_println!("\nreproduce with STRESS_SEED={}", seed);
+    hypoxide::test_utils::test_panic_handler(info)
+}
+
+const STEPS: usize = 4000;
+const MAX_LIVE: usize = 64;
+const TEST_HEAP_SIZE: usize = 256 * 1024;
+
+/// Every allocator gets the same heap in turn. Growth is not enabled, so they stay within it.
+static mut TEST_HEAP: [u8; TEST_HEAP_SIZE] = [0; TEST_HEAP_SIZE];
+
+fn test_heap() -> (usize, usize) {
+    (&raw mut TEST_HEAP as usize, TEST_HEAP_SIZE)
+}
+
+/// xorshift64*, which is plenty random for picking sizes.
+struct Rng(u64);
+
+impl Rng {
+    /// Takes the seed from STRESS_SEED, or the time stamp counter so that every run differs.
+    fn new() -> Self {
+        let seed = match FIXED_SEED {
+            Some(seed) => seed.parse().expect("STRESS_SEED must be a number"),
+            None => unsafe { core::arch::x86_64::_rdtsc() },
+        };
+        // xorshift gets stuck on 0
+        let seed = seed.max(1);
+        SEED.store(seed, Ordering::Relaxed);
+        Rng(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns a number in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// Mostly small sizes, with the occasional large one, like a kernel would allocate.
+    fn size(&mut self) -> usize {
+        match self.below(10) {
+            0..=5 => 1 + self.below(64),
+            6..=8 => 1 + self.below(2048),
+            _ => 1 + self.below(8192),
+        }
+    }
+
+    fn layout(&mut self) -> Layout {
+        let align = 1 << self.below(9);
+        Layout::from_size_align(self.size(), align).unwrap()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Block {
+    ptr: *mut u8,
+    layout: Layout,
+    /// Every byte of the block is filled with this
+    fill: u8,
+}
+
+/// Runs random allocs, reallocs and deallocs against `allocator`, checking after every step that
+/// live blocks are aligned, do not overlap and keep their contents, and that the allocator's
+/// bookkeeping is consistent.
+fn stress<A>(allocator: &Locked<A>)
+where
+    Locked<A>: GlobalAlloc,
+    A: AllocatorStats + Validate,
+{
+    stress_through(allocator, allocator);
+}
+
+/// Like stress, but allocates through `allocator`, which wraps `inner`, and checks the bookkeeping
+/// of `inner`.
+fn stress_through<G, A>(allocator: &G, inner: &Locked<A>)
+where
+    G: GlobalAlloc,
+    A: AllocatorStats + Validate,
+{
+    let mut rng = Rng::new();
+    let mut live: [Option<Block>; MAX_LIVE] = [None; MAX_LIVE];
+
+    for step in 0..STEPS {
+        let slot = rng.below(MAX_LIVE);
+        match (live[slot], rng.below(4)) {
+            (None, _) => {
+                let layout = rng.layout();
+                let ptr = unsafe { allocator.alloc(layout) };
+                if !ptr.is_null() {
+                    live[slot] = Some(new_block(ptr, layout, step, &live));
+                }
+            }
+            (Some(block), 0) => {
+                let new_size = rng.size();
+                let new_layout = Layout::from_size_align(new_size, block.layout.align()).unwrap();
+                let ptr = unsafe { allocator.realloc(block.ptr, block.layout, new_size) };
+                if ptr.is_null() {
+                    check_fill(&block);
+                } else {
+                    // the part both layouts cover must have been copied
+                    let kept = Block {
+                        ptr,
+                        layout: Layout::from_size_align(
+                            new_size.min(block.layout.size()),
+                            block.layout.align(),
+                        )
+                        .unwrap(),
+                        fill: block.fill,
+                    };
+                    check_fill(&kept);
+                    live[slot] = None;
+                    live[slot] = Some(new_block(ptr, new_layout, step, &live));
+                }
+            }
+            (Some(block), _) => {
+                check_fill(&block);
+                unsafe { allocator.dealloc(block.ptr, block.layout) };
+                live[slot] = None;
+            }
+        }
+
+        if let Err(corruption) = inner.lock().validate() {
+            panic!("step {step}: {corruption}");
+        }
+        let live_count = live.iter().flatten().count();
+        assert_eq!(
+            inner.lock().stats().live_allocations,
+            live_count,
+            "step {step}: live allocations"
+        );
+        if step % 64 == 0 {
+            live.iter().flatten().for_each(check_fill);
+        }
+    }
+
+    for block in live.iter().flatten() {
+        check_fill(block);
+        unsafe { allocator.dealloc(block.ptr, block.layout) };
+    }
+    inner.lock().validate().unwrap();
+    assert_eq!(inner.lock().stats().live_allocations, 0);
+}
+
+/// Checks a fresh allocation against the live blocks and fills it.
+fn new_block(ptr: *mut u8, layout: Layout, step: usize, live: &[Option<Block>]) -> Block {
+    let start = ptr as usize;
+    let end = start + layout.size();
+    assert_eq!(
+        start % layout.align(),
+        0,
+        "step {step}: misaligned {start:#x}"
+    );
+    for other in live.iter().flatten() {
+        let other_start = other.ptr as usize;
+        let other_end = other_start + other.layout.size();
+        assert!(
+            end <= other_start || other_end <= start,
+            "step {step}: {start:#x}..{end:#x} overlaps {other_start:#x}..{other_end:#x}"
+        );
+    }
+
+    let block = Block {
+        ptr,
+        layout,
+        fill: step as u8,
+    };
+    unsafe { ptr.write_bytes(block.fill, layout.size()) };
+    block
+}
+
+fn check_fill(block: &Block) {
+    for offset in 0..block.layout.size() {
+        let byte = unsafe { block.ptr.add(offset).read() };
+        assert_eq!(
+            byte, block.fill,
+            "block at {:p} overwritten at +{offset}",
+            block.ptr
+        );
+    }
+}
+
+#[test_case]
+fn bump() {
+    let allocator = Locked::new(BumpAllocator::new());
+    let (start, size) = test_heap();
+    unsafe { allocator.lock().init(start, size) };
+    stress(&allocator);
+}
+
+#[test_case]
+fn linked_list_first_fit() {
+    let allocator = Locked::new(LinkedListAllocator::with_policy(FitPolicy::First));
+    let (start, size) = test_heap();
+    unsafe { allocator.lock().init(start, size) };
+    stress(&allocator);
+}
+
+#[test_case]
+fn linked_list_best_fit() {
+    let allocator = Locked::new(LinkedListAllocator::with_policy(FitPolicy::Best));
+    let (start, size) = test_heap();
+    unsafe { allocator.lock().init(start, size) };
+    stress(&allocator);
+}
+
+#[test_case]
+fn linked_list_worst_fit() {
+    let allocator = Locked::new(LinkedListAllocator::with_policy(FitPolicy::Worst));
+    let (start, size) = test_heap();
+    unsafe { allocator.lock().init(start, size) };
+    stress(&allocator);
+}
+
+#[test_case]
+fn linked_list_next_fit() {
+    let allocator = Locked::new(LinkedListAllocator::with_policy(FitPolicy::Next));
+    let (start, size) = test_heap();
+    unsafe { allocator.lock().init(start, size) };
+    stress(&allocator);
+}
+
+#[test_case]
+fn fixed_size_block() {
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    let (start, size) = test_heap();
+    unsafe { allocator.lock().init(start, size) };
+    stress(&allocator);
+}
+
+#[test_case]
+fn external_linked_list() {
+    let allocator = Locked::new(ExternalLinkedListAllocator::new());
+    let (start, size) = test_heap();
+    unsafe { allocator.lock().init(start, size) };
+    stress(&allocator);
+}
+
+#[test_case]
+fn tlsf() {
+    let allocator = Locked::new(TlsfAllocator::new());
+    let (start, size) = test_heap();
+    unsafe { allocator.lock().init(start, size) };
+    stress(&allocator);
+}
+
+#[test_case]
+fn debug_wrapped() {
+    let allocator = DebugAllocator::new(Locked::new(FixedSizeBlockAllocator::new()));
+    let (start, size) = test_heap();
+    unsafe { allocator.lock().init(start, size) };
+    stress_through(&allocator, &allocator);
+    allocator.check_live().unwrap();
+}
@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hypoxide::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec};
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use hypoxide::memory::demand_paging::{self, ReserveError};
+use x86_64::VirtAddr;
+
+/// Unused part of the address space, reserved by the tests below
+const RESERVED_START: u64 = 0x_5555_0000_0000;
+const RESERVED_SIZE: u64 = 16 * 4096;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hypoxide::allocator;
+    use hypoxide::memory::{self, buddy::BuddyFrameAllocator};
+
+    hypoxide::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_demand_paged_heap().expect("heap initialization failed");
+
+    demand_paging::reserve(VirtAddr::new(RESERVED_START), RESERVED_SIZE)
+        .expect("reserving test range failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hypoxide::test_utils::test_panic_handler(info)
+}
+
+#[test_case]
+fn reserved_pages_are_zeroed_on_first_access() {
+    let ptr = RESERVED_START as *const u64;
+    for i in 0..(RESERVED_SIZE as usize / 8) {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, 0);
+    }
+}
+
+#[test_case]
+fn reserved_pages_keep_their_contents() {
+    let ptr = (RESERVED_START + 4096) as *mut u64;
+    unsafe { ptr.write_volatile(0xdead_beef) };
+    assert_eq!(unsafe { ptr.read_volatile() }, 0xdead_beef);
+}
+
+#[test_case]
+fn overlapping_reservation_fails() {
+    let overlapping = VirtAddr::new(RESERVED_START + RESERVED_SIZE - 4096);
+    assert_eq!(
+        demand_paging::reserve(overlapping, 2 * 4096),
+        Err(ReserveError::Overlap)
+    );
+}
+
+#[test_case]
+fn is_reserved() {
+    assert!(demand_paging::is_reserved(VirtAddr::new(RESERVED_START)));
+    assert!(!demand_paging::is_reserved(VirtAddr::new(
+        RESERVED_START + RESERVED_SIZE
+    )));
+}
+
+#[test_case]
+fn demand_paged_heap() {
+    let heap_value = Box::new(41);
+    assert_eq!(*heap_value, 41);
+
+    let vec = vec![1u8; 512 * 1024];
+    assert_eq!(vec.iter().map(|&x| x as usize).sum::<usize>(), 512 * 1024);
+}
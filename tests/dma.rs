@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hypoxide::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use hypoxide::memory::{
+    bitmap::{BELOW_4GIB, BELOW_16MIB},
+    dma::{self, DmaBuffer},
+    page_tables,
+    vmalloc::VmallocError,
+};
+use x86_64::{PhysAddr, VirtAddr, structures::paging::PageTableFlags};
+
+/// Set by main, so that the tests can read physical memory
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hypoxide::memory::{self, buddy::BuddyFrameAllocator};
+
+    hypoxide::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let memory_map = unsafe { dma::init(&boot_info.memory_map, phys_mem_offset) };
+    let frame_allocator = unsafe { BuddyFrameAllocator::init(&memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hypoxide::test_utils::test_panic_handler(info)
+}
+
+#[test_case]
+fn buffer_is_physically_contiguous() {
+    let buffer = DmaBuffer::new([0u32; 3 * 1024], None).unwrap();
+    for page in 0..3u64 {
+        let translation = page_tables::translate(buffer.virt_addr() + page * 4096).unwrap();
+        assert_eq!(translation.frame, buffer.phys_addr() + page * 4096);
+    }
+}
+
+#[test_case]
+fn buffer_is_write_back() {
+    // the same memory type as the physical memory mapping, which must not be aliased
+    let buffer = DmaBuffer::new(0u64, None).unwrap();
+    let flags = page_tables::translate(buffer.virt_addr()).unwrap().flags;
+    let cache_flags = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    assert!((flags & cache_flags).is_empty());
+}
+
+#[test_case]
+fn device_sees_writes() {
+    let mut buffer = DmaBuffer::new([1u64; 4], Some(BELOW_4GIB)).unwrap();
+    assert!(buffer.phys_addr() + 32u64 <= BELOW_4GIB);
+    buffer[3] = 0xdead_beef;
+
+    // read the way a device would, by physical address
+    let offset = PHYS_MEM_OFFSET.load(Ordering::Relaxed);
+    let phys: *const u64 = VirtAddr::new(offset + buffer.phys_addr().as_u64()).as_ptr();
+    assert_eq!(unsafe { phys.read_volatile() }, 1);
+    assert_eq!(unsafe { phys.add(3).read_volatile() }, 0xdead_beef);
+}
+
+#[test_case]
+fn frames_freed_on_drop() {
+    let before = dma::free_frames().unwrap();
+    let buffer = DmaBuffer::new([0u8; 2 * 4096], None).unwrap();
+    let virt_addr = buffer.virt_addr();
+    assert_eq!(dma::free_frames(), Some(before - 2));
+    drop(buffer);
+
+    assert_eq!(page_tables::translate(virt_addr), None);
+    assert_eq!(dma::free_frames(), Some(before));
+}
+
+/// A request the zone cannot meet must not use up any of it.
+#[test_case]
+fn unmet_limit_leaves_zone_usable() {
+    let before = dma::free_frames().unwrap();
+    // frame 0 is never usable, so nothing lies below this limit
+    let limit = PhysAddr::new(4096);
+    assert!(matches!(
+        DmaBuffer::new(0u64, Some(limit)),
+        Err(VmallocError::OutOfFrames)
+    ));
+    assert_eq!(dma::free_frames(), Some(before));
+
+    let buffer = DmaBuffer::new(0u64, Some(BELOW_16MIB)).unwrap();
+    assert!(buffer.phys_addr() + 8u64 <= BELOW_16MIB);
+}
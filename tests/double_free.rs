@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    panic::PanicInfo,
+};
+use hypoxide::{
+    allocator::{Locked, debug::DebugAllocator, linked_list::LinkedListAllocator},
+    qemu::{QemuExitCode, exit_qemu},
+    serial_print, serial_println,
+    test_utils::panic_message_contains,
+};
+
+static mut TEST_HEAP: [u8; 4096] = [0; 4096];
+
+/// LinkedListAllocator catches some double frees itself, so the report has to come from
+/// DebugAllocator for the test to pass.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if panic_message_contains(info, "heap corruption detected: double free of") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hypoxide::hlt_loop();
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    double_free();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    hypoxide::hlt_loop();
+}
+
+fn double_free() {
+    serial_print!("double_free::double_free...\t");
+    let allocator = DebugAllocator::new(Locked::new(LinkedListAllocator::new()));
+    unsafe {
+        allocator.lock().init(&raw mut TEST_HEAP as usize, 4096);
+
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let ptr = allocator.alloc(layout);
+        allocator.dealloc(ptr, layout);
+        allocator.dealloc(ptr, layout);
+    }
+}
@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use hypoxide::{
+    allocator::{HEAP_MAX_SIZE, HEAP_START},
+    memory::KERNEL_MEMORY,
+    qemu::{QemuExitCode, exit_qemu},
+    serial_print, serial_println,
+    test_utils::panic_message_contains,
+};
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if panic_message_contains(info, "while KERNEL_MEMORY is locked") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hypoxide::hlt_loop();
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hypoxide::allocator;
+    use hypoxide::memory::{self, buddy::BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    hypoxide::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_demand_paged_heap().expect("heap initialization failed");
+
+    fault_while_locked();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    hypoxide::hlt_loop();
+}
+
+/// Touching an unbacked heap page while holding KERNEL_MEMORY must panic instead of deadlocking.
+fn fault_while_locked() {
+    serial_print!("fault_while_locked::fault_while_locked...\t");
+    let _kernel_memory = KERNEL_MEMORY.lock();
+    // the allocator only touches the start of the heap, its last page is still unbacked
+    let page = (HEAP_START + HEAP_MAX_SIZE - 1) as *mut u8;
+    unsafe { page.write_volatile(1) };
+}
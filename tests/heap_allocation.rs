@@ -6,23 +6,27 @@
 
 extern crate alloc;
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
 use bootloader::{BootInfo, entry_point};
 use core::panic::PanicInfo;
 use hypoxide::allocator::HEAP_SIZE;
 
+const MIB: u64 = 1024 * 1024;
+
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use hypoxide::allocator;
-    use hypoxide::memory::{self, BootInfoFrameAllocator};
+    use hypoxide::memory::{self, buddy::BuddyFrameAllocator};
     use x86_64::VirtAddr;
 
     hypoxide::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
 
     test_main();
     loop {}
@@ -51,6 +55,89 @@ fn large_vec() {
     assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
 }
 
+#[test_case]
+fn heap_grows_past_initial_size() {
+    let vec = vec![0u8; 4 * HEAP_SIZE];
+    assert_eq!(vec.len(), 4 * HEAP_SIZE);
+    assert_eq!(vec[4 * HEAP_SIZE - 1], 0);
+}
+
+/// A failed attempt to grow the heap must not leave any of its pages mapped, otherwise every later
+/// attempt fails on them.
+#[test_case]
+fn failed_growth_is_rolled_back() {
+    use hypoxide::{allocator::HEAP_START, memory::KERNEL_MEMORY};
+    use x86_64::{
+        VirtAddr,
+        structures::paging::{
+            FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+        },
+    };
+
+    // a page in the way of the heap's growth makes mapping it fail half way
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(HEAP_START as u64 + MIB));
+    {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let kernel_memory = kernel_memory.as_mut().unwrap();
+        let frame = kernel_memory.frame_allocator.allocate_frame().unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe {
+            kernel_memory
+                .mapper
+                .map_to(page, frame, flags, &mut kernel_memory.frame_allocator)
+                .unwrap()
+                .flush()
+        };
+    }
+    let mut vec: Vec<u8> = Vec::new();
+    assert!(vec.try_reserve_exact(4 * MIB as usize).is_err());
+
+    {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let kernel_memory = kernel_memory.as_mut().unwrap();
+        let (frame, flush) = kernel_memory.mapper.unmap(page).unwrap();
+        flush.flush();
+        unsafe { kernel_memory.frame_allocator.deallocate_frame(frame) };
+    }
+    assert!(vec.try_reserve_exact(4 * MIB as usize).is_ok());
+}
+
+/// Only the global allocator may grow into the pages after the kernel heap, an allocator on a
+/// buffer from the heap must not.
+#[test_case]
+fn nested_allocator_does_not_grow() {
+    use core::alloc::{GlobalAlloc, Layout};
+    use hypoxide::allocator::{Locked, linked_list::LinkedListAllocator};
+
+    let mut buffer = vec![0u8; 4096];
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(buffer.as_mut_ptr() as usize, buffer.len())
+    };
+
+    let layout = Layout::from_size_align(2 * 4096, 8).unwrap();
+    assert!(unsafe { allocator.alloc(layout) }.is_null());
+}
+
+#[test_case]
+fn stats_track_live_allocations() {
+    use hypoxide::allocator::heap_stats;
+
+    let before = heap_stats();
+    let heap_value = Box::new(41);
+    let during = heap_stats();
+    assert_eq!(during.live_allocations, before.live_allocations + 1);
+    assert!(during.allocated_bytes > before.allocated_bytes);
+
+    drop(heap_value);
+    let after = heap_stats();
+    assert_eq!(after.live_allocations, before.live_allocations);
+    assert_eq!(after.allocated_bytes, before.allocated_bytes);
+    assert!(after.peak_allocated_bytes >= during.allocated_bytes);
+}
+
 #[test_case]
 fn many_boxes() {
     for i in 0..(2 * HEAP_SIZE) {
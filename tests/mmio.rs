@@ -0,0 +1,126 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hypoxide::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use hypoxide::memory::{
+    mmio::{self, CacheMode},
+    page_tables,
+};
+use volatile::Volatile;
+use x86_64::{
+    PhysAddr, VirtAddr, registers::model_specific::Msr, structures::paging::PageTableFlags,
+};
+
+/// Set by main, so that the tests can read physical memory
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The VGA text buffer, the only device memory QEMU is guaranteed to have
+const VGA_BUFFER: u64 = 0xb8000;
+
+/// The last cell of the VGA text buffer, which the tests scribble on
+const LAST_CELL: usize = (25 * 80 - 1) * 2;
+
+type TextBuffer = [[Volatile<u16>; 80]; 25];
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hypoxide::memory::{self, buddy::BuddyFrameAllocator};
+
+    hypoxide::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hypoxide::test_utils::test_panic_handler(info)
+}
+
+/// Reads the last cell through the physical memory mapping.
+fn last_cell_through_phys() -> u16 {
+    let offset = PHYS_MEM_OFFSET.load(Ordering::Relaxed);
+    let addr = VirtAddr::new(offset + VGA_BUFFER + LAST_CELL as u64);
+    unsafe { addr.as_ptr::<u16>().read_volatile() }
+}
+
+#[test_case]
+fn typed_access() {
+    let mut buffer =
+        unsafe { mmio::map::<TextBuffer>(PhysAddr::new(VGA_BUFFER), 4000, CacheMode::Uncached) }
+            .unwrap();
+    buffer[24][79].write(0x0f21);
+    assert_eq!(last_cell_through_phys(), 0x0f21);
+    assert_eq!(buffer[24][79].read(), 0x0f21);
+}
+
+#[test_case]
+fn offset_access() {
+    let mut buffer =
+        unsafe { mmio::map::<()>(PhysAddr::new(VGA_BUFFER), 4000, CacheMode::WriteThrough) }
+            .unwrap();
+    buffer.write::<u16>(LAST_CELL, 0x0f22);
+    assert_eq!(last_cell_through_phys(), 0x0f22);
+    assert_eq!(buffer.read::<u16>(LAST_CELL), 0x0f22);
+}
+
+#[test_case]
+fn unaligned_start() {
+    let buffer = unsafe {
+        mmio::map::<()>(
+            PhysAddr::new(VGA_BUFFER + LAST_CELL as u64),
+            2,
+            CacheMode::Uncached,
+        )
+    }
+    .unwrap();
+    assert_eq!(buffer.virt_addr().as_u64() % 4096, LAST_CELL as u64);
+    assert_eq!(buffer.read::<u16>(0), last_cell_through_phys());
+}
+
+#[test_case]
+fn mapped_with_cache_mode() {
+    for mode in [
+        CacheMode::Uncached,
+        CacheMode::WriteThrough,
+        CacheMode::WriteCombining,
+    ] {
+        let buffer = unsafe { mmio::map::<()>(PhysAddr::new(VGA_BUFFER), 4000, mode) }.unwrap();
+        let flags = page_tables::translate(buffer.virt_addr()).unwrap().flags;
+        let cache_flags = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+        assert_eq!(flags & cache_flags, mode.flags());
+    }
+}
+
+#[test_case]
+fn pat_programmed_by_memory_init() {
+    // PAT entry 2 (selected by NO_CACHE alone) is write-combining, the others keep their reset
+    // values
+    let pat = unsafe { Msr::new(0x277).read() };
+    assert_eq!((pat >> 16) & 0xff, 0x01);
+    assert_eq!(pat & !(0xff << 16), 0x0007_0406_0007_0406 & !(0xff << 16));
+}
+
+#[test_case]
+fn unmapped_on_drop() {
+    let buffer =
+        unsafe { mmio::map::<()>(PhysAddr::new(VGA_BUFFER), 4000, CacheMode::Uncached) }.unwrap();
+    let addr = buffer.virt_addr();
+    assert!(page_tables::translate(addr).is_some());
+    drop(buffer);
+    assert_eq!(page_tables::translate(addr), None);
+}
@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hypoxide::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{format, vec, vec::Vec};
+use bootloader::{BootInfo, entry_point};
+use core::{
+    alloc::Layout,
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use hypoxide::allocator::{
+    HEAP_MAX_SIZE,
+    oom::{AllocErrorReport, register_pressure_callback, unregister_pressure_callback},
+};
+use spin::Mutex;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hypoxide::allocator;
+    use hypoxide::memory::{self, buddy::BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    hypoxide::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hypoxide::test_utils::test_panic_handler(info)
+}
+
+static PRESSURE_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_pressure() -> usize {
+    PRESSURE_CALLS.fetch_add(1, Ordering::Relaxed);
+    0
+}
+
+#[test_case]
+fn pressure_callbacks_run_before_failing() {
+    register_pressure_callback(count_pressure).unwrap();
+    let before = PRESSURE_CALLS.load(Ordering::Relaxed);
+
+    let mut vec = Vec::<u8>::new();
+    assert!(vec.try_reserve_exact(2 * HEAP_MAX_SIZE).is_err());
+    assert_eq!(PRESSURE_CALLS.load(Ordering::Relaxed), before + 1);
+
+    assert!(unregister_pressure_callback(count_pressure));
+    assert!(vec.try_reserve_exact(2 * HEAP_MAX_SIZE).is_err());
+    assert_eq!(PRESSURE_CALLS.load(Ordering::Relaxed), before + 1);
+}
+
+/// Memory which can be given back under memory pressure, like a cache would.
+static BALLAST: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+fn drop_ballast() -> usize {
+    BALLAST.lock().take().map_or(0, |ballast| ballast.len())
+}
+
+#[test_case]
+fn allocation_retried_after_pressure_relief() {
+    *BALLAST.lock() = Some(vec![0u8; HEAP_MAX_SIZE * 5 / 8]);
+    register_pressure_callback(drop_ballast).unwrap();
+
+    // does not fit next to the ballast, the heap cannot grow that far
+    let mut vec = Vec::<u8>::new();
+    assert!(vec.try_reserve_exact(HEAP_MAX_SIZE / 2).is_ok());
+    assert!(BALLAST.lock().is_none());
+    assert!(unregister_pressure_callback(drop_ballast));
+}
+
+#[test_case]
+fn unregister_unknown_callback() {
+    assert!(!unregister_pressure_callback(drop_ballast));
+}
+
+#[test_case]
+fn report_describes_failed_allocation() {
+    let layout = Layout::from_size_align(12345, 64).unwrap();
+    let report = format!("{}", AllocErrorReport::new(layout));
+    assert!(report.contains("failed to allocate 12345 bytes with align 64"));
+    assert!(report.contains("largest free block"));
+}
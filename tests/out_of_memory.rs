@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use hypoxide::{
+    allocator::HEAP_MAX_SIZE,
+    qemu::{QemuExitCode, exit_qemu},
+    serial_print, serial_println,
+    test_utils::panic_message_contains,
+};
+
+/// Larger than the heap can ever grow, spelled out in `panic` below.
+const SIZE: usize = 64 * 1024 * 1024;
+const _: () = assert!(SIZE > HEAP_MAX_SIZE);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let is_report = panic_message_contains(info, "failed to allocate 67108864 bytes with align 1")
+        && panic_message_contains(info, "heap limit:")
+        && panic_message_contains(info, "largest free block:");
+    if is_report {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hypoxide::hlt_loop();
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    out_of_memory();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    hypoxide::hlt_loop();
+}
+
+/// The alloc error handler must panic with its report instead of aborting.
+fn out_of_memory() {
+    serial_print!("out_of_memory::out_of_memory...\t");
+    let vec = Vec::<u8>::with_capacity(SIZE);
+    serial_println!("allocated {} bytes", vec.capacity());
+}
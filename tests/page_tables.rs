@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hypoxide::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{BootInfo, entry_point};
+use core::{
+    fmt,
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use hypoxide::memory::page_tables::{self, MappingSize};
+use x86_64::{VirtAddr, structures::paging::PageTableFlags};
+
+/// Set by main, so that the tests can read physical memory
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hypoxide::allocator;
+    use hypoxide::memory::{self, buddy::BuddyFrameAllocator};
+
+    hypoxide::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_demand_paged_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hypoxide::test_utils::test_panic_handler(info)
+}
+
+fn phys_mem_offset() -> VirtAddr {
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed))
+}
+
+#[test_case]
+fn translated_address_holds_same_value() {
+    let value: u64 = 0x1234_5678;
+    let translation = page_tables::translate(VirtAddr::from_ptr(&value)).unwrap();
+    let through_phys = phys_mem_offset() + translation.phys_addr.as_u64();
+    assert_eq!(
+        unsafe { through_phys.as_ptr::<u64>().read_volatile() },
+        value
+    );
+}
+
+#[test_case]
+fn physical_memory_mapping() {
+    let translation = page_tables::translate(phys_mem_offset() + 0xb8000u64).unwrap();
+    assert_eq!(translation.phys_addr.as_u64(), 0xb8000);
+}
+
+#[test_case]
+fn heap_is_writable() {
+    let value = Box::new(41);
+    let translation = page_tables::translate(VirtAddr::from_ptr(&*value)).unwrap();
+    assert_eq!(translation.size, MappingSize::Size4KiB);
+    assert!(translation.flags.contains(PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn unmapped_address() {
+    assert_eq!(
+        page_tables::translate(VirtAddr::new(0x_5555_0000_0000)),
+        None
+    );
+}
+
+/// Counts the lines written to it.
+struct LineCounter(usize);
+
+impl fmt::Write for LineCounter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.matches('\n').count();
+        Ok(())
+    }
+}
+
+#[test_case]
+fn dump_and_table_frames() {
+    let mut lines = LineCounter(0);
+    page_tables::dump(&mut lines).unwrap();
+    assert!(lines.0 > 0);
+
+    let frames = page_tables::table_frames().unwrap();
+    assert_eq!(frames.level_4, 1);
+    assert!(frames.level_1 > 0);
+}
@@ -0,0 +1,136 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hypoxide::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{BootInfo, entry_point};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use hypoxide::allocator::slab_cache::SlabCache;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hypoxide::allocator;
+    use hypoxide::memory::{self, buddy::BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    hypoxide::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_demand_paged_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hypoxide::test_utils::test_panic_handler(info)
+}
+
+/// An odd-sized object, which FixedSizeBlockAllocator would round up to 32 bytes.
+#[derive(Debug, PartialEq)]
+struct Inode {
+    number: u64,
+    size: u64,
+    links: u32,
+}
+
+fn new_inode() -> Inode {
+    Inode {
+        number: 0,
+        size: 0,
+        links: 1,
+    }
+}
+
+#[test_case]
+fn objects_are_constructed() {
+    let cache = SlabCache::new("inode", new_inode);
+    let inode = cache.alloc().unwrap();
+    assert_eq!(*inode, new_inode());
+}
+
+#[test_case]
+fn objects_are_packed_at_their_size() {
+    let cache = SlabCache::new("inode", new_inode);
+    assert_eq!(cache.stats().object_size, 24);
+
+    let first = cache.alloc().unwrap();
+    let second = cache.alloc().unwrap();
+    assert_eq!(
+        &*second as *const Inode as usize,
+        &*first as *const Inode as usize + 24
+    );
+}
+
+#[test_case]
+fn destructor_runs_on_drop() {
+    static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+    let cache = SlabCache::new("inode", new_inode).with_destructor(|inode| {
+        assert_eq!(inode.number, 7);
+        DESTROYED.fetch_add(1, Ordering::Relaxed);
+    });
+
+    let mut inode = cache.alloc().unwrap();
+    inode.number = 7;
+    drop(inode);
+    assert_eq!(DESTROYED.load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn freed_objects_are_reused() {
+    let cache = SlabCache::new("inode", new_inode);
+    let inode = cache.alloc().unwrap();
+    let addr = &*inode as *const Inode as usize;
+    drop(inode);
+
+    let inode = cache.alloc().unwrap();
+    assert_eq!(&*inode as *const Inode as usize, addr);
+    assert_eq!(cache.stats().slabs, 1);
+}
+
+#[test_case]
+fn stats_follow_alloc_and_free() {
+    let cache = SlabCache::new("inode", new_inode);
+    let per_slab = SlabCache::<Inode>::OBJECTS_PER_SLAB;
+
+    let mut inodes: Vec<_> = (0..per_slab + 1).map(|_| cache.alloc().unwrap()).collect();
+    let stats = cache.stats();
+    assert_eq!(stats.slabs, 2);
+    assert_eq!(stats.live_objects, per_slab + 1);
+    assert_eq!(stats.free_objects, per_slab - 1);
+
+    inodes.truncate(1);
+    let stats = cache.stats();
+    assert_eq!(stats.live_objects, 1);
+    assert_eq!(stats.free_objects, 2 * per_slab - 1);
+}
+
+#[test_case]
+fn reclaim_frees_only_empty_slabs() {
+    let cache = SlabCache::new("inode", new_inode);
+    let per_slab = SlabCache::<Inode>::OBJECTS_PER_SLAB;
+
+    let mut inodes: Vec<_> = (0..3 * per_slab).map(|_| cache.alloc().unwrap()).collect();
+    assert_eq!(cache.stats().slabs, 3);
+
+    // keep one object of the first slab alive
+    inodes.truncate(1);
+    assert_eq!(cache.reclaim(), 2);
+    assert_eq!(cache.stats().slabs, 1);
+
+    drop(inodes);
+    assert_eq!(cache.reclaim(), 1);
+    assert_eq!(cache.stats().slabs, 0);
+}
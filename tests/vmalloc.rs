@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hypoxide::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use hypoxide::memory::{
+    page_tables,
+    vmalloc::{self, Purpose, VMALLOC_START, VmallocError},
+};
+use x86_64::{PhysAddr, VirtAddr, structures::paging::PageTableFlags};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hypoxide::memory::{self, buddy::BuddyFrameAllocator};
+
+    hypoxide::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hypoxide::test_utils::test_panic_handler(info)
+}
+
+const WRITABLE: PageTableFlags = PageTableFlags::WRITABLE;
+
+#[test_case]
+fn mapped_range_is_zeroed_and_writable() {
+    let range = vmalloc::map(3 * 4096, Purpose::Stack, WRITABLE).unwrap();
+    assert!(range.start.as_u64() >= VMALLOC_START);
+
+    let ptr: *mut u64 = range.start.as_mut_ptr();
+    for i in 0..(range.size as usize / 8) {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, 0);
+        unsafe { ptr.add(i).write_volatile(i as u64) };
+    }
+    assert_eq!(unsafe { ptr.add(1000).read_volatile() }, 1000);
+
+    unsafe { vmalloc::free(range.start) }.unwrap();
+}
+
+#[test_case]
+fn free_unmaps_range_and_returns_frames() {
+    let range = vmalloc::map(4096, Purpose::Heap, WRITABLE).unwrap();
+    let frame = page_tables::translate(range.start).unwrap().frame;
+    unsafe { range.start.as_mut_ptr::<u64>().write_volatile(0xdead_beef) };
+
+    assert_eq!(unsafe { vmalloc::free(range.start) }, Ok(range));
+    assert_eq!(page_tables::translate(range.start), None);
+    assert_eq!(vmalloc::find(range.start), None);
+
+    // the frame allocator hands out the most recently freed frame first, zeroed
+    let again = vmalloc::map(4096, Purpose::Heap, WRITABLE).unwrap();
+    assert_eq!(page_tables::translate(again.start).unwrap().frame, frame);
+    assert_eq!(unsafe { again.start.as_ptr::<u64>().read_volatile() }, 0);
+    unsafe { vmalloc::free(again.start) }.unwrap();
+}
+
+#[test_case]
+fn ranges_do_not_overlap() {
+    let a = vmalloc::map(2 * 4096, Purpose::Stack, WRITABLE).unwrap();
+    let b = vmalloc::map(4096, Purpose::Stack, WRITABLE).unwrap();
+    assert!(a.end() < b.start || b.end() < a.start);
+    // the guard page between them stays unmapped
+    assert_eq!(page_tables::translate(a.end()), None);
+    assert_eq!(vmalloc::find(a.start + 4096u64), Some(a));
+
+    unsafe {
+        vmalloc::free(a.start).unwrap();
+        vmalloc::free(b.start).unwrap();
+    }
+}
+
+#[test_case]
+fn map_phys_shares_physical_memory() {
+    let range =
+        unsafe { vmalloc::map_phys(PhysAddr::new(0xb8000), 4096, Purpose::Framebuffer, WRITABLE) }
+            .unwrap();
+    assert_eq!(
+        page_tables::translate(range.start).unwrap().frame,
+        PhysAddr::new(0xb8000)
+    );
+    assert!(!range.owns_frames);
+    unsafe { vmalloc::free(range.start) }.unwrap();
+}
+
+#[test_case]
+fn reserved_range_is_not_mapped() {
+    let range = vmalloc::reserve(4096, Purpose::Mmio).unwrap();
+    assert_eq!(page_tables::translate(range.start), None);
+    unsafe { vmalloc::free(range.start) }.unwrap();
+    assert_eq!(
+        unsafe { vmalloc::free(range.start) },
+        Err(VmallocError::NotAllocated)
+    );
+}